@@ -0,0 +1,328 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use reqwest::Client;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use common::carrier::{Carrier, CarrierResponse};
+use common::crypto::{Cipher, Direction, Psk};
+use common::encoding::Decoder;
+use common::session::{self, Handshake, ServerHello, SessionId};
+use common::structs::{ClientMessage, ServerMessage};
+
+use crate::servers::Servers;
+
+/// Why a single exchange with the server failed
+enum ExchangeError {
+    Rejected,            // The reply did not authenticate, so the server did not accept our session
+    Unreachable(String), // The request never reached the server, so another one may take it
+    Failed(String),      // Anything else
+    Unrepeatable,        // The session was rejected after the server may have acted on the message
+}
+
+impl From<String> for ExchangeError {
+    fn from(e: String) -> Self {
+        ExchangeError::Failed(e)
+    }
+}
+
+/// An established session with the server
+#[derive(Clone)]
+struct ActiveSession {
+    id: SessionId,
+    cipher: Cipher,
+    next_sequence: Arc<AtomicU64>, // Numbers each message once, as the server insists
+    established: Instant,
+    rotate_after: Duration,
+}
+
+/// The disguised, encrypted link to the masquerade servers. Each server has its
+/// own session, negotiated lazily on first use and again whenever it is due for
+/// rotation.
+pub struct Channel {
+    client: Client,
+    carrier: Arc<dyn Carrier>,
+    psk: Psk,
+    handshake_cipher: Cipher,
+    servers: Servers,
+    sessions: Vec<Mutex<Option<ActiveSession>>>, // One per server
+    pinned: std::sync::Mutex<Option<usize>>,     // The server tunnel traffic currently goes to
+}
+
+impl Channel {
+    pub fn new(client: Client, carrier: Arc<dyn Carrier>, psk: &Psk, servers: Servers) -> Result<Self, String> {
+        Ok(Channel {
+            client,
+            carrier,
+            psk: psk.clone(),
+            handshake_cipher: Cipher::from_psk(psk)?,
+            sessions: (0..servers.len()).map(|_| Mutex::new(None)).collect(),
+            servers,
+            pinned: std::sync::Mutex::new(None),
+        })
+    }
+
+    /// Sends a message to a server and waits for its reply. Proxy requests go
+    /// wherever the selection policy says and fail over to the next server if one
+    /// cannot be reached. Tunnel traffic only means something to the server that
+    /// holds the tunnels, so it stays on one server until that server fails.
+    pub async fn send(&self, message: &ClientMessage) -> Result<ServerMessage, String> {
+        self.send_tracked(message).await.map(|(_, reply)| reply)
+    }
+
+    /// Like `send`, but also says which server answered, for follow-up messages
+    /// that only that server can make sense of
+    pub async fn send_tracked(&self, message: &ClientMessage) -> Result<(usize, ServerMessage), String> {
+        if let ClientMessage::Mux { .. } = message {
+            let index = self.pinned();
+            return self.send_at(index, message).await.map(|reply| (index, reply));
+        }
+
+        let order = self.servers.order();
+        let mut last_error = String::new();
+        for (attempt, &index) in order.iter().enumerate() {
+            // Failed servers are only tried when none is left up
+            if attempt > 0 && !self.servers.is_up(index) {
+                break;
+            }
+            match self.send_to(index, message).await {
+                Ok(response) => return Ok((index, response)),
+                Err((e, true)) => last_error = e,
+                Err((e, false)) => return Err(e),
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Sends a message to one particular server, with no failover
+    pub async fn send_at(&self, index: usize, message: &ClientMessage) -> Result<ServerMessage, String> {
+        self.send_to(index, message).await.map_err(|(e, _)| e)
+    }
+
+    /// The server for tunnel traffic, moving to another if it has failed
+    fn pinned(&self) -> usize {
+        let mut pinned = self.pinned.lock().unwrap();
+        match *pinned {
+            Some(index) if self.servers.is_up(index) => index,
+            previous => {
+                let index = self.servers.preferred();
+                if previous.is_some() && previous != Some(index) {
+                    // The new server knows nothing of our tunnels, so they will be reset
+                    println!("🛰️ Moving tunnels to server {}", self.servers.get(index));
+                }
+                *pinned = Some(index);
+                index
+            }
+        }
+    }
+
+    /// Sends a message to one server, keeping its health up to date. Errors say
+    /// whether the request never reached the server.
+    async fn send_to(&self, index: usize, message: &ClientMessage) -> Result<ServerMessage, (String, bool)> {
+        let started = Instant::now();
+        let result = self.send_with_session(index, message).await;
+
+        match &result {
+            Ok(_) => {
+                // Long polls are held by the server, so they say nothing about latency
+                let held = matches!(message, ClientMessage::Mux { wait: true, .. });
+                self.servers.succeeded(index, (!held).then(|| started.elapsed()));
+            }
+            Err(ExchangeError::Unreachable(e)) | Err(ExchangeError::Failed(e)) => self.servers.failed(index, e),
+            Err(ExchangeError::Rejected) | Err(ExchangeError::Unrepeatable) => {}
+        }
+
+        result.map_err(|e| match e {
+            ExchangeError::Rejected => ("Server rejected a fresh session".to_string(), false),
+            ExchangeError::Unreachable(e) => (e, true),
+            ExchangeError::Failed(e) => (e, false),
+            ExchangeError::Unrepeatable => {
+                ("Server rejected the session, and the request is not safe to send again".to_string(), false)
+            }
+        })
+    }
+
+    async fn send_with_session(&self, index: usize, message: &ClientMessage) -> Result<ServerMessage, ExchangeError> {
+        let session = self.session(index, None).await?;
+        match self.send_in(index, &session, message).await {
+            Err(ExchangeError::Rejected) => {
+                // The server may have restarted or expired the session; try once more with a fresh one.
+                // A reply that does not open does not prove the message went unprocessed, though,
+                // so only messages that are harmless to repeat are sent again.
+                println!("🔁 Session rejected by server, renegotiating");
+                let session = self.session(index, Some(&session.id)).await?;
+                if !is_repeatable(message) {
+                    return Err(ExchangeError::Unrepeatable);
+                }
+                self.send_in(index, &session, message).await
+            }
+            result => result,
+        }
+    }
+
+    async fn send_in(&self, index: usize, session: &ActiveSession, message: &ClientMessage) -> Result<ServerMessage, ExchangeError> {
+        let sequence = session.next_sequence.fetch_add(1, Ordering::Relaxed);
+        let sealed = session.cipher.seal_json(Direction::ClientToServer, sequence, message)?;
+        let reply = self.exchange(index, &session::frame(&session.id, sequence, &sealed)).await?;
+
+        // A server that does not know our session answers with its decoy site instead
+        let plaintext = session
+            .cipher
+            .open(Direction::ServerToClient, sequence, &reply)
+            .map_err(|_| ExchangeError::Rejected)?;
+        Ok(serde_json::from_slice(&plaintext).map_err(|e| e.to_string())?)
+    }
+
+    /// Returns the current session, performing a handshake if there is none, it is
+    /// due for rotation, or it is the `stale` one the server just rejected
+    async fn session(&self, index: usize, stale: Option<&SessionId>) -> Result<ActiveSession, ExchangeError> {
+        let mut current = self.sessions[index].lock().await;
+
+        if let Some(session) = current.as_ref() {
+            let rejected = stale == Some(&session.id);
+            if !rejected && session.established.elapsed() < session.rotate_after {
+                return Ok(session.clone());
+            }
+        }
+
+        let session = self.handshake(index).await?;
+        *current = Some(session.clone());
+        Ok(session)
+    }
+
+    async fn handshake(&self, index: usize) -> Result<ActiveSession, ExchangeError> {
+        let handshake = Handshake::new();
+        let hello = self.handshake_cipher.seal_json(Direction::ClientToServer, 0, &handshake.hello())?;
+
+        let reply = self.exchange(index, &hello).await.map_err(|e| match e {
+            ExchangeError::Rejected => {
+                ExchangeError::Failed("Server rejected the handshake (check the pre-shared key)".to_string())
+            }
+            e => e,
+        })?;
+        let reply: ServerHello = self
+            .handshake_cipher
+            .open_json(Direction::ServerToClient, 0, &reply)
+            .map_err(|_| "Server rejected the handshake (check the pre-shared key)".to_string())?;
+        let (id, cipher) = handshake.finish(&self.psk, &reply)?;
+
+        println!("🤝 Session established with server {}", self.servers.get(index));
+        Ok(ActiveSession {
+            id,
+            cipher,
+            next_sequence: Arc::new(AtomicU64::new(1)),
+            established: Instant::now(),
+            // Rotate well before the server would expire the session
+            rotate_after: Duration::from_secs(reply.lifetime / 2),
+        })
+    }
+
+    /// Sends one payload through the carrier and returns the payload of the reply
+    async fn exchange(&self, index: usize, payload: &[u8]) -> Result<Vec<u8>, ExchangeError> {
+        let encoded = self.carrier.encode_request(payload)?;
+        let server = self.servers.get(index);
+
+        let mut url = format!("{}{}", server.base, encoded.path);
+        if let Some(query) = &encoded.query {
+            url.push('?');
+            url.push_str(query);
+        }
+
+        let method = reqwest::Method::from_bytes(encoded.method.as_bytes()).map_err(|e| e.to_string())?;
+        let mut request = self.client.request(method, &url).body(encoded.body);
+        for (name, value) in &encoded.headers {
+            request = request.header(name, value);
+        }
+        if let Some(host) = &server.host {
+            request = request.header(reqwest::header::HOST, host);
+        }
+
+        let proxy_response = request.send().await.map_err(|e| {
+            if e.is_connect() {
+                ExchangeError::Unreachable(e.to_string())
+            } else {
+                ExchangeError::Failed(e.to_string())
+            }
+        })?;
+
+        let status = proxy_response.status().as_u16();
+        let headers: Vec<(String, String)> = proxy_response
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or_default().to_string()))
+            .collect();
+
+        // Anything in between may have compressed the reply; codings we cannot undo are left to the carrier
+        let content_encoding = proxy_response
+            .headers()
+            .get_all(reqwest::header::CONTENT_ENCODING)
+            .iter()
+            .map(|v| v.to_str().unwrap_or("unknown"))
+            .collect::<Vec<_>>()
+            .join(",");
+        let body = proxy_response.bytes().await.map_err(|e| e.to_string())?;
+        let decompressed_data = match Decoder::new(&content_encoding) {
+            Some(decoder) => decoder.decode_all(&body).map_err(|e| e.to_string())?,
+            None => body.to_vec(),
+        };
+
+        self.carrier
+            .decode_response(&CarrierResponse {
+                status,
+                headers,
+                body: decompressed_data,
+            })
+            .map_err(|_| ExchangeError::Rejected)
+    }
+}
+
+/// Whether a message does no harm if the server sees it twice
+fn is_repeatable(message: &ClientMessage) -> bool {
+    match message {
+        ClientMessage::Proxy(request) => {
+            ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"].contains(&request.method.as_str())
+        }
+        ClientMessage::Body { .. } => false, // Each fetch moves the body along
+        ClientMessage::Mux { .. } => true,   // Frames carry offsets, so the server drops ones it has seen
+    }
+}
+
+/// The rest of a response body, held by the server that sent its first piece
+/// and fetched from it a piece at a time
+pub struct BodyPieces {
+    channel: Arc<Channel>,
+    server: usize,
+    stream: u64,
+    done: bool,
+}
+
+impl BodyPieces {
+    pub fn new(channel: Arc<Channel>, server: usize, stream: u64) -> Self {
+        BodyPieces {
+            channel,
+            server,
+            stream,
+            done: false,
+        }
+    }
+
+    /// The next piece of the body, or None once it is complete. Pieces may be
+    /// empty when the origin is slow to send more.
+    pub async fn next(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let message = ClientMessage::Body { stream: self.stream };
+        match self.channel.send_at(self.server, &message).await? {
+            ServerMessage::Body { data, last } => {
+                self.done = last;
+                BASE64.decode(data).map(Some).map_err(|e| format!("Undecodable body piece: {}", e))
+            }
+            ServerMessage::Error { message, .. } => Err(message),
+            other => Err(format!("Unexpected reply to a body fetch: {:?}", other)),
+        }
+    }
+}
@@ -1,223 +1,408 @@
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use flate2::read::{DeflateDecoder, GzDecoder};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::time::{timeout, Duration};
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 use reqwest::Client;
-use httparse::Request as HttpParseRequest;
-use serde_json::json;
-use std::io::Read;
-use std::collections::HashMap;
 use std::error::Error;
-use serde::Deserialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
 use clap::Parser;
 
+use common::carrier::{Carrier, CarrierKind, CarrierOptions};
+use common::config;
+use common::crypto::Psk;
+use common::structs::{decode_headers, encode_headers, ClientMessage, ErrorKind, ProxyRequest, ServerMessage};
+
+mod channel;
+mod mitm;
+mod mux;
+mod request;
+mod response;
+mod servers;
+mod socks;
+mod tunnel;
+mod udp;
+use channel::{BodyPieces, Channel};
+use mitm::Mitm;
+use mux::Mux;
+use request::{HttpRequest, Limits, ReadError};
+use response::Response;
+use servers::{Selection, Server, Servers};
+use socks::Credentials;
+
 #[derive(Parser)]
 struct Cli {
+    /// TOML file setting any of these options, keyed by their long names (see docs/configuration.md)
+    #[clap(long = "config")]
+    config: Option<PathBuf>,
+
     #[clap(short = 'p', long = "port", default_value = "8080")]
     port: u16,
+
+    /// Address to listen on, e.g. 0.0.0.0 to serve other machines on the network
+    #[clap(long = "bind", default_value = "127.0.0.1")]
+    bind: IpAddr,
+
+    /// Protocol spoken on the local port: http, socks5, or auto to detect it from the first byte
+    #[clap(short = 'm', long = "mode", default_value = "auto")]
+    mode: ListenMode,
+
+    /// Username SOCKS5 clients must authenticate with (no authentication if omitted)
+    #[clap(long = "socks-user", requires = "socks_pass")]
+    socks_user: Option<String>,
+
+    /// Password SOCKS5 clients must authenticate with
+    #[clap(long = "socks-pass", requires = "socks_user")]
+    socks_pass: Option<String>,
+
+    /// Masquerade server to send traffic to, as URL[;host=NAME] where NAME replaces the Host
+    /// header (repeat for several servers, e.g. -s https://a.example/app -s https://b.example)
+    #[clap(short = 's', long = "server", default_value = "http://localhost:3030")]
+    servers: Vec<Server>,
+
+    /// Extra PEM certificate to trust for https servers, e.g. one the server generated for itself
+    #[clap(long = "server-ca")]
+    server_ca: Option<PathBuf>,
+
+    /// How requests are spread over several servers: failover, round-robin or latency
+    #[clap(long = "server-selection", default_value = "failover")]
+    selection: Selection,
+
+    /// Carrier used to disguise proxy traffic: query, body, header, png or html (must match the server)
+    #[clap(short = 'c', long = "carrier", default_value = "body")]
+    carrier: CarrierKind,
+
+    /// Directory of PNG cover images for the png carrier (generated covers are used if omitted)
+    #[clap(long = "cover-dir")]
+    cover_dir: Option<PathBuf>,
+
+    /// Directory of HTML page templates for the html carrier (a built-in article is used if omitted)
+    #[clap(long = "template-dir")]
+    template_dir: Option<PathBuf>,
+
+    /// Pre-shared key used to authenticate and encrypt traffic (must match the server)
+    #[clap(long = "psk")]
+    psk: String,
+
+    /// Largest request line and header block accepted from the browser, in bytes
+    #[clap(long = "max-header-size", default_value = "65536")]
+    max_header_size: usize,
+
+    /// Most header fields accepted in one request
+    #[clap(long = "max-headers", default_value = "100")]
+    max_headers: usize,
+
+    /// Largest request body accepted from the browser, in bytes
+    #[clap(long = "max-body-size", default_value = "10485760")]
+    max_body_size: usize,
+
+    /// Intercept HTTPS: terminate TLS for CONNECT targets with certificates from a local CA
+    /// and forward the decrypted requests individually
+    #[clap(long = "mitm")]
+    mitm: bool,
+
+    /// PEM certificate of the interception CA (generated along with --ca-key if missing)
+    #[clap(long = "ca-cert", default_value = "masquerade-ca.pem")]
+    ca_cert: PathBuf,
+
+    /// PEM private key of the interception CA
+    #[clap(long = "ca-key", default_value = "masquerade-ca-key.pem")]
+    ca_key: PathBuf,
+
+    /// Hosts tunnelled without interception, e.g. for certificate pinning (comma separated, *.domain allowed)
+    #[clap(long = "mitm-bypass", value_delimiter = ',')]
+    mitm_bypass: Vec<String>,
+
+    /// Seconds a browser connection may sit idle between requests before it is closed
+    #[clap(long = "idle-timeout", default_value = "60")]
+    idle_timeout: u64,
+}
+
+/// Which protocol the local listener speaks
+#[derive(Clone, Copy, PartialEq)]
+enum ListenMode {
+    Auto,
+    Http,
+    Socks5,
 }
 
-#[derive(Deserialize, Debug)]
-struct ProxyResponse {
-    status: u16,
-    headers: HashMap<String, String>,
-    body: String,
+impl FromStr for ListenMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(ListenMode::Auto),
+            "http" => Ok(ListenMode::Http),
+            "socks5" => Ok(ListenMode::Socks5),
+            _ => Err(format!("Unknown mode: {} (expected auto, http or socks5)", s)),
+        }
+    }
+}
+
+impl fmt::Display for ListenMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ListenMode::Auto => "auto",
+            ListenMode::Http => "http",
+            ListenMode::Socks5 => "socks5",
+        };
+        f.write_str(name)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
 
-    let args = Cli::parse();
-    let port = args.port;
+    let args: Cli = config::parse("MASQUERADE_CLIENT");
+    let (bind, port) = (args.bind, args.port);
 
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
+    let listener = TcpListener::bind((bind, port)).await?;
     
     println!("      \x1b[1m\x1b[31m._______.\x1b[0m");
     println!("      \x1b[1m\x1b[31m| \\   / |\x1b[0m              Masquerade Proxy Client");
     println!("   .--\x1b[1m\x1b[31m|.O.|.O.|\x1b[32m______.\x1b[0m       v{}", env!("CARGO_PKG_VERSION"));
     println!("__). -\x1b[1m\x1b[31m| = | = |\x1b[32m/   \\ |\x1b[0m");
-    println!(">__)  \x1b[1m\x1b[31m(.'---`.)\x1b[32mQ.|.Q.|\x1b[0m--.    127.0.0.1:{}", port); 
-    println!("       \x1b[1m\x1b[31m\\\\___//\x1b[32m = | = |\x1b[0m-.(__  127.0.0.1:{}", port);
+    println!(">__)  \x1b[1m\x1b[31m(.'---`.)\x1b[32mQ.|.Q.|\x1b[0m--.    {}:{}", bind, port); 
+    println!("       \x1b[1m\x1b[31m\\\\___//\x1b[32m = | = |\x1b[0m-.(__  {}:{}", bind, port);
     println!("        \x1b[1m\x1b[31m`---'\x1b[32m( .---. )\x1b[0m (__<");
     println!("              \x1b[1m\x1b[32m\\\\.-.//\x1b[0m        Listening on port {}", port);
     println!("               \x1b[1m\x1b[32m`---'\x1b[0m");
 
-    let client = Client::new();
-    
+    let mut builder = Client::builder();
+    if let Some(path) = &args.server_ca {
+        let pem = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+    }
+    let client = builder.build()?;
+    let options = CarrierOptions {
+        cover_dir: args.cover_dir,
+        template_dir: args.template_dir,
+        max_payload: None, // Response bodies arrive a piece at a time
+    };
+    let carrier: Arc<dyn Carrier> = Arc::from(args.carrier.build(&options)?);
+    println!("🎭 Using {} carrier", args.carrier);
+    for server in &args.servers {
+        println!("🛰️ Server: {}", server);
+    }
+    if args.servers.len() > 1 {
+        println!("🔀 Choosing between servers by {}", args.selection);
+    }
+    let servers = Servers::new(args.servers, args.selection)?;
+    let psk = Psk::stretch(&args.psk)?;
+    let channel = Arc::new(Channel::new(client, carrier, &psk, servers)?);
+    let mux = Mux::start(channel.clone());
+
+    let mitm = if args.mitm {
+        let mitm = Mitm::load_or_generate(&args.ca_cert, &args.ca_key, args.mitm_bypass)?;
+        println!("🕵️ Intercepting HTTPS with the CA in {}", args.ca_cert.display());
+        Some(mitm)
+    } else {
+        None
+    };
+
+    let idle_timeout = Duration::from_secs(args.idle_timeout);
+    let context = Arc::new(Context {
+        channel,
+        mux,
+        limits: Limits {
+            max_header_bytes: args.max_header_size,
+            max_headers: args.max_headers,
+            max_body_bytes: args.max_body_size,
+        },
+        idle_timeout,
+        mitm,
+    });
+
+    let mode = args.mode;
+    let credentials = match (args.socks_user, args.socks_pass) {
+        (Some(username), Some(password)) => Some(Arc::new(Credentials { username, password })),
+        (None, None) => None,
+        // Half a login would leave the listener open to anyone
+        _ => return Err("--socks-user and --socks-pass must be given together".into()),
+    };
+    println!("🔌 Listening in {} mode", mode);
+
     loop {
-        let (mut stream, addr) = listener.accept().await?;
+        let (stream, addr) = listener.accept().await?;
         println!("\n➡️  New connection from: {}", addr);
-        
-        let client = client.clone();
-        
+
+        let context = context.clone();
+        let credentials = credentials.clone();
+
         tokio::spawn(async move {
-            let mut buffer = [0; 4096];
-            
-            match stream.read(&mut buffer).await {
-                Ok(n) => {
-                    if n == 0 { return; }
-                    
-                    let mut headers = [httparse::EMPTY_HEADER; 64];
-                    let mut req = HttpParseRequest::new(&mut headers);
-                    
-                    if let Ok(_) = req.parse(&buffer[..n]) {
-                        let method = req.method.unwrap_or("GET");
-                        let target_url = req.path.unwrap_or("/");
-                        
-                        println!("\n🌐 {} {} HTTP/1.1", method, target_url);
-
-                        // Create header map once and reuse it
-                        let mut header_map = HashMap::new();
-                        for header in req.headers {
-                            header_map.insert(
-                                header.name.to_string(),
-                                String::from_utf8_lossy(header.value).to_string()
-                            );
-                        }
-                        
-                        // Handle HTTPS CONNECT requests
-                        if method == "CONNECT" {
-                            println!("🔒 HTTPS CONNECT request for: {}", target_url);
-                            handle_connect(&mut stream, target_url).await;
-                            return;
-                        }
-
-                        println!("🎯 Target URL: {}", target_url);
-                        
-                        // Encode parameters for proxy request
-                        let encoded_url = BASE64.encode(&target_url);
-                        let encoded_headers = BASE64.encode(json!(header_map).to_string());
-                        
-                        let body_start = find_body_start(&buffer[..n]);
-                        let encoded_body = if let Some(start) = body_start {
-                            let body = &buffer[start..n];
-                            println!("📦 Request body ({} bytes)", body.len());
-                            BASE64.encode(body)
-                        } else {
-                            println!("📦 No request body");
-                            "".to_string()
-                        };
-                        
-                        let proxy_url = format!(
-                            "http://localhost:3030/proxy?\
-                            target={}&\
-                            method={}&\
-                            headers={}&\
-                            body={}",
-                            encoded_url,
-                            method,
-                            encoded_headers,
-                            encoded_body
-                        );
-                        
-                        println!("📤 Forwarding to proxy server...");
-                        
-                        // Forward request to proxy server
-                        match client.get(&proxy_url).send().await {
-                            Ok(proxy_response) => {
-
-                                let status = proxy_response.status();
-                                println!("📥 Proxy response: {} {}", 
-                                    status.as_u16(), 
-                                    status.canonical_reason().unwrap_or("")
-                                );
-                                
-                                let headers: String = proxy_response.headers()
-                                    .iter()
-                                    .map(|(name, value)| {
-                                        format!(
-                                            "{}: {}\r\n",
-                                            name,
-                                            value.to_str().unwrap_or_default()
-                                        )
-                                    })
-                                    .collect();
-
-                                let content_encoding = proxy_response.headers().get(reqwest::header::CONTENT_ENCODING);
-                                let mut decompressed_data = Vec::new();
-                            
-                                // Handle different content encoding types (gzip, deflate)
-                                match content_encoding.and_then(|v| v.to_str().ok()) {
-                                    Some("gzip") => {
-                                        let compressed_data = proxy_response.bytes().await.unwrap();
-                                        let mut decoder = GzDecoder::new(&compressed_data[..]);
-                                        decoder.read_to_end(&mut decompressed_data).unwrap();
-                                    }
-                                    Some("deflate") => {
-                                        let compressed_data = proxy_response.bytes().await.unwrap();
-                                        let mut decoder = DeflateDecoder::new(&compressed_data[..]);
-                                        decoder.read_to_end(&mut decompressed_data).unwrap();
-                                    }
-                                    _ => {
-                                        decompressed_data = proxy_response.bytes().await.unwrap().to_vec();
-                                    }
-                                }
-
-                                let decoded = serde_json::from_slice::<ProxyResponse>(&decompressed_data).unwrap();
-                                let decoded_status = http::StatusCode::from_u16(decoded.status).unwrap();
-                                let decoded_headers = decoded.headers.iter().map(|(k, v)| format!("{}: {}\r\n", k, v)).collect::<String>();
-                                let decoded_body = BASE64.decode(&decoded.body).unwrap();
-
-                                let status_line = format!(
-                                    "HTTP/1.1 {} {}\r\n",
-                                    decoded_status.as_u16(),
-                                    decoded_status.canonical_reason().unwrap_or("")
-                                );
-
-                                let _ = stream.write_all(status_line.as_bytes()).await;
-                                let _ = stream.write_all(decoded_headers.as_bytes()).await;
-                                let _ = stream.write_all(b"\r\n").await;
-                                let _ = stream.write_all(&decoded_body).await;
-                                
-                            }
-                            Err(e) => {
-                                println!("❌ Proxy request failed: {}", e);
-                                let _ = stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
-                            }
-                        }
-                    }
+            // SOCKS5 greetings start with the version byte, which no HTTP method does
+            let socks = match mode {
+                ListenMode::Socks5 => true,
+                ListenMode::Http => false,
+                ListenMode::Auto => {
+                    let mut first = [0; 1];
+                    matches!(timeout(idle_timeout, stream.peek(&mut first)).await, Ok(Ok(1)) if first[0] == socks::VERSION)
                 }
-                Err(e) => eprintln!("❌ Error reading from socket: {}", e),
+            };
+
+            if socks {
+                socks::handle_socks(stream, &context.mux, credentials.as_deref()).await;
+            } else {
+                handle_connection(stream, &context).await;
             }
         });
     }
 }
 
-async fn handle_connect(client_stream: &mut TcpStream, addr: &str) {
-    println!("🔐 Establishing HTTPS tunnel to {}", addr);
-    
-    match TcpStream::connect(addr).await {
-        Ok(mut server_stream) => {
-            println!("✅ Connected to target server");
-            let response = "HTTP/1.1 200 Connection Established\r\n\r\n";
-            if client_stream.write_all(response.as_bytes()).await.is_ok() {
-                println!("🔄 Starting bidirectional tunnel");
-                
-                match tokio::io::copy_bidirectional(client_stream, &mut server_stream).await {
-                    Ok((from_client, from_server)) => {
-                        println!("🔄 Tunnel closed. Bytes transferred:");
-                        println!("   Client → Server: {} bytes", from_client);
-                        println!("   Server → Client: {} bytes", from_server);
-                    }
-                    Err(e) => println!("❌ Error in HTTPS tunnel: {}", e),
+/// What every connection handler shares
+pub struct Context {
+    pub channel: Arc<Channel>,
+    pub mux: Arc<Mux>,
+    pub limits: Limits,
+    pub idle_timeout: Duration,
+    pub mitm: Option<Mitm>,
+}
+
+/// Serves an HTTP proxy connection, handing CONNECT requests to a tunnel or,
+/// in interception mode, to the TLS interceptor
+async fn handle_connection(mut stream: TcpStream, context: &Context) {
+    let Some(connect) = serve(&mut stream, context, None).await else {
+        return;
+    };
+    println!("🔒 HTTPS CONNECT request for: {}", connect.target);
+
+    match &context.mitm {
+        Some(mitm) if !mitm.bypasses(&connect.target) => mitm::intercept(stream, &connect.target, mitm, context).await,
+        _ => tunnel::handle_connect(stream, &connect.target, &context.mux).await,
+    }
+}
+
+/// Serves requests from one browser connection until it closes, asks to close,
+/// or sits idle for too long. Pipelined requests are answered in order.
+/// `origin` is the scheme and authority of intercepted connections, whose
+/// requests only carry a path. Otherwise a CONNECT request ends the loop and is
+/// returned to the caller.
+pub async fn serve<S>(stream: &mut S, context: &Context, origin: Option<&str>) -> Option<HttpRequest>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buffer = Vec::new();
+
+    loop {
+        let read = timeout(context.idle_timeout, request::read_request(stream, &mut buffer, &context.limits)).await;
+        let mut request = match read {
+            Ok(Ok(request)) => request,
+            Ok(Err(ReadError::Closed)) => return None,
+            Ok(Err(e)) => {
+                println!("❌ {}", e);
+                if let Some(response) = e.response() {
+                    let _ = stream.write_all(&response).await;
                 }
+                return None;
             }
+            Err(_) => {
+                if !buffer.is_empty() {
+                    println!("⏳ Timed out waiting for the rest of a request");
+                }
+                return None;
+            }
+        };
+
+        println!("\n🌐 {} {} HTTP/1.{}", request.method, request.target, request.minor_version);
+
+        if request.method == "CONNECT" {
+            if origin.is_none() {
+                return Some(request);
+            }
+            // Nested tunnels inside an intercepted connection are not supported
+            let _ = response::write_response(stream, &request, Response::status(405), false).await;
+            return None;
         }
-        Err(e) => {
-            println!("❌ Failed to connect to target server: {}", e);
-            let _ = client_stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+        if let Some(origin) = origin {
+            if request.target.starts_with('/') {
+                request.target = format!("{}{}", origin, request.target);
+            }
+        }
+
+        let keep_alive = response::keep_alive(&request);
+        let response = forward(&request, &context.channel).await;
+
+        match response::write_response(stream, &request, response, keep_alive).await {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => {
+                println!("❌ Failed to relay the response: {}", e);
+                return None;
+            }
         }
     }
 }
 
-fn find_body_start(buffer: &[u8]) -> Option<usize> {
-    let mut i = 0;
-    while i < buffer.len() - 3 {
-        if &buffer[i..i+4] == b"\r\n\r\n" {
-            return Some(i + 4);
+/// Sends a plain HTTP request through the proxy server and returns the response to relay
+async fn forward(request: &HttpRequest, channel: &Arc<Channel>) -> Response {
+    println!("🎯 Target URL: {}", request.target);
+    if request.body.is_empty() {
+        println!("📦 No request body");
+    } else {
+        println!("📦 Request body ({} bytes)", request.body.len());
+    }
+
+    // The body has already been de-chunked and the browser told to continue
+    let headers = request
+        .headers
+        .iter()
+        .filter(|(name, _)| !name.eq_ignore_ascii_case("transfer-encoding") && !name.eq_ignore_ascii_case("expect"))
+        .map(|(name, value)| (name.as_str(), value.as_slice()));
+
+    let proxy_request = ProxyRequest {
+        target: request.target.clone(),
+        method: request.method.clone(),
+        headers: encode_headers(headers),
+        body: Some(BASE64.encode(&request.body)),
+    };
+
+    println!("📤 Forwarding to proxy server...");
+
+    // Forward request to proxy server
+    match channel.send_tracked(&ClientMessage::Proxy(proxy_request)).await {
+        Ok((server, ServerMessage::Proxy(decoded))) => {
+            println!("📥 Proxy response: {}", decoded.status);
+            match (BASE64.decode(&decoded.body), decode_headers(&decoded.headers)) {
+                (Ok(body), Ok(headers)) => Response {
+                    status: decoded.status,
+                    reason: decoded.reason,
+                    headers,
+                    body,
+                    rest: decoded.stream.map(|stream| BodyPieces::new(channel.clone(), server, stream)),
+                },
+                (Err(e), _) => {
+                    println!("❌ Proxy server returned an undecodable body ({} bytes): {}", decoded.body.len(), e);
+                    Response::status(502)
+                }
+                (_, Err(e)) => {
+                    println!("❌ Proxy server returned undecodable headers: {}", e);
+                    Response::status(502)
+                }
+            }
+        }
+        Ok((_, ServerMessage::Error { kind, message })) => {
+            let status = match kind {
+                ErrorKind::Denied => 403,
+                ErrorKind::RequestTooLarge => 413,
+                ErrorKind::ResponseTooLarge | ErrorKind::Failed => 502,
+            };
+            println!("{} {}", if kind == ErrorKind::Denied { "⛔" } else { "❌" }, message);
+            Response {
+                headers: vec![("Content-Type".to_string(), b"text/plain".to_vec())],
+                body: message.into_bytes(),
+                ..Response::status(status)
+            }
+        }
+        Ok((_, other)) => {
+            println!("❌ Unexpected {} from proxy server", other.name());
+            Response::status(502)
+        }
+        Err(e) => {
+            println!("❌ Proxy request failed: {}", e);
+            Response::status(502)
         }
-        i += 1;
     }
-    None
 }
-
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose,
+    SerialNumber,
+};
+use time::{Duration, OffsetDateTime};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use common::files;
+
+use crate::Context;
+
+const CA_NAME: &str = "Masquerade Proxy Local CA";
+const CA_VALIDITY_DAYS: i64 = 10 * 365;
+const LEAF_VALIDITY_DAYS: i64 = 90;
+const LEAF_CACHE_SIZE: usize = 1000; // Leaf configurations kept before the cache is emptied
+
+/// Terminates TLS for CONNECT targets with leaf certificates issued on the fly
+/// by a local CA, which the browser has to be told to trust
+pub struct Mitm {
+    ca: Certificate,
+    ca_key: KeyPair,
+    leaf_key: KeyPair, // Shared by every leaf; generating a key per host is slow and buys nothing here
+    leaves: Mutex<HashMap<String, Arc<ServerConfig>>>,
+    bypass: Vec<String>,
+}
+
+impl Mitm {
+    /// Loads the CA from `cert_path` and `key_path`, or creates and saves one if neither exists
+    pub fn load_or_generate(cert_path: &Path, key_path: &Path, bypass: Vec<String>) -> Result<Self, String> {
+        let (ca, ca_key) = match (cert_path.exists(), key_path.exists()) {
+            (true, true) => load_ca(cert_path, key_path)?,
+            (false, false) => generate_ca(cert_path, key_path)?,
+            _ => {
+                return Err(format!(
+                    "Only one of {} and {} exists; provide both or neither",
+                    cert_path.display(),
+                    key_path.display()
+                ))
+            }
+        };
+
+        Ok(Mitm {
+            ca,
+            ca_key,
+            leaf_key: KeyPair::generate().map_err(|e| e.to_string())?,
+            leaves: Mutex::new(HashMap::new()),
+            bypass: bypass.into_iter().map(|h| h.trim().to_ascii_lowercase()).collect(),
+        })
+    }
+
+    /// Whether a CONNECT target is on the bypass list and must be tunnelled untouched
+    pub fn bypasses(&self, authority: &str) -> bool {
+        let host = host_of(authority).to_ascii_lowercase();
+        self.bypass.iter().any(|pattern| match pattern.strip_prefix("*.") {
+            Some(domain) => host.ends_with(&format!(".{}", domain)),
+            None => host == *pattern,
+        })
+    }
+
+    /// The TLS configuration presenting a certificate for `host`
+    fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>, String> {
+        let mut leaves = self.leaves.lock().unwrap();
+        if let Some(config) = leaves.get(host) {
+            return Ok(config.clone());
+        }
+
+        let mut params = CertificateParams::new(vec![host.to_string()]).map_err(|e| e.to_string())?;
+        params.distinguished_name.push(DnType::CommonName, host);
+        params.serial_number = Some(SerialNumber::from(rand::random::<u64>()));
+        params.not_before = OffsetDateTime::now_utc() - Duration::days(1);
+        params.not_after = OffsetDateTime::now_utc() + Duration::days(LEAF_VALIDITY_DAYS);
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature, KeyUsagePurpose::KeyEncipherment];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        let leaf = params
+            .signed_by(&self.leaf_key, &self.ca, &self.ca_key)
+            .map_err(|e| e.to_string())?;
+
+        // The browser already has the CA, so the leaf alone is presented
+        let chain = vec![leaf.der().clone()];
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.leaf_key.serialize_der()));
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(|e| e.to_string())?;
+        // Requests are read as HTTP/1.1, so do not let the browser pick HTTP/2
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let config = Arc::new(config);
+        if leaves.len() >= LEAF_CACHE_SIZE {
+            leaves.clear();
+        }
+        leaves.insert(host.to_string(), config.clone());
+        Ok(config)
+    }
+}
+
+/// Accepts the CONNECT, performs the TLS handshake as `authority` and serves the
+/// decrypted requests as ordinary proxy requests
+pub async fn intercept(mut stream: TcpStream, authority: &str, mitm: &Mitm, context: &Context) {
+    let config = match mitm.server_config(host_of(authority)) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("❌ Failed to issue certificate for {}: {}", authority, e);
+            let _ = stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n").await;
+            return;
+        }
+    };
+
+    if stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await.is_err() {
+        return;
+    }
+
+    let mut tls = match TlsAcceptor::from(config).accept(stream).await {
+        Ok(tls) => tls,
+        Err(e) => {
+            // Usually the browser does not trust the CA, or the site pins its certificate
+            println!("❌ TLS handshake with browser failed for {}: {}", authority, e);
+            return;
+        }
+    };
+    println!("🕵️ Intercepting HTTPS for {}", authority);
+
+    let origin = match authority.strip_suffix(":443") {
+        Some(host) => format!("https://{}", host),
+        None => format!("https://{}", authority),
+    };
+    crate::serve(&mut tls, context, Some(&origin)).await;
+    let _ = tls.shutdown().await;
+}
+
+/// The host part of host:port, without IPv6 brackets
+fn host_of(authority: &str) -> &str {
+    let host = match authority.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => authority,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn load_ca(cert_path: &Path, key_path: &Path) -> Result<(Certificate, KeyPair), String> {
+    let cert_pem = std::fs::read_to_string(cert_path).map_err(|e| format!("Failed to read {}: {}", cert_path.display(), e))?;
+    let key_pem = std::fs::read_to_string(key_path).map_err(|e| format!("Failed to read {}: {}", key_path.display(), e))?;
+
+    let key = KeyPair::from_pem(&key_pem).map_err(|e| format!("Invalid CA key {}: {}", key_path.display(), e))?;
+    let params = CertificateParams::from_ca_cert_pem(&cert_pem)
+        .map_err(|e| format!("Invalid CA certificate {}: {}", cert_path.display(), e))?;
+    // Re-signing reproduces the issuer details needed to sign leaves; the browser trusts the file on disk
+    let ca = params.self_signed(&key).map_err(|e| e.to_string())?;
+    Ok((ca, key))
+}
+
+fn generate_ca(cert_path: &Path, key_path: &Path) -> Result<(Certificate, KeyPair), String> {
+    let key = KeyPair::generate().map_err(|e| e.to_string())?;
+
+    let mut params = CertificateParams::default();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, CA_NAME);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+    params.serial_number = Some(SerialNumber::from(rand::random::<u64>()));
+    params.not_before = OffsetDateTime::now_utc() - Duration::days(1);
+    params.not_after = OffsetDateTime::now_utc() + Duration::days(CA_VALIDITY_DAYS);
+    let ca = params.self_signed(&key).map_err(|e| e.to_string())?;
+
+    std::fs::write(cert_path, ca.pem()).map_err(|e| format!("Failed to write {}: {}", cert_path.display(), e))?;
+    files::write_private(key_path, &key.serialize_pem())?;
+    println!("🔏 Generated a new interception CA; import {} into your browser to trust it", cert_path.display());
+    Ok((ca, key))
+}
+
@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, Duration};
+
+use common::mux::{DatagramReceiver, Endpoint, Frame};
+use common::structs::{ClientMessage, ServerMessage};
+
+use crate::channel::Channel;
+
+const MAX_IN_FLIGHT: usize = 2; // A held poll plus one exchange carrying fresh data
+const RETRY_DELAY: u64 = 500; // Pause after a failed exchange (milliseconds)
+const RETRANSMIT_CHECK: u64 = 500; // How often to look for frames due for retransmission (milliseconds)
+
+/// Multiplexes every tunnelled stream and UDP association over one series of
+/// exchanges with the server. A background scheduler batches outgoing frames and
+/// keeps a poll outstanding while any are open, so the server always has a
+/// request to answer.
+pub struct Mux {
+    endpoint: Arc<Endpoint>,
+    next_stream: AtomicU32,
+}
+
+impl Mux {
+    pub fn start(channel: Arc<Channel>) -> Arc<Self> {
+        let endpoint = Arc::new(Endpoint::new(false));
+        tokio::spawn(schedule(endpoint.clone(), channel, rand::random()));
+        Arc::new(Mux {
+            endpoint,
+            next_stream: AtomicU32::new(1),
+        })
+    }
+
+    /// Has the server connect a new stream to `target`
+    pub async fn open(&self, target: &str) -> Result<u32, String> {
+        let stream = self.next_stream.fetch_add(1, Ordering::Relaxed);
+        self.endpoint.open(stream, target).await?;
+        Ok(stream)
+    }
+
+    /// Starts a UDP association, returning its id and where datagrams from the server arrive
+    pub fn associate(&self) -> (u32, DatagramReceiver) {
+        let association = self.next_stream.fetch_add(1, Ordering::Relaxed);
+        (association, self.endpoint.associate(association))
+    }
+
+    /// Sends a datagram to `peer` (host:port) from the server
+    pub fn send_datagram(&self, association: u32, peer: &str, data: &[u8]) {
+        self.endpoint.send_datagram(association, peer, data);
+    }
+
+    pub fn dissociate(&self, association: u32) {
+        self.endpoint.dissociate(association, true);
+    }
+
+    /// Relays an open stream to `socket` until both sides close. Returns the bytes
+    /// sent and received on the socket.
+    pub async fn attach(&self, stream: u32, socket: TcpStream) -> (u64, u64) {
+        self.endpoint.attach(stream, socket).await
+    }
+}
+
+async fn schedule(endpoint: Arc<Endpoint>, channel: Arc<Channel>, connection: u64) {
+    let mut in_flight = JoinSet::new();
+    let mut resync = false;
+
+    loop {
+        let streams_open = endpoint.is_active();
+        let has_outbound = resync || endpoint.has_outbound();
+
+        // Send whenever there is something to say, and otherwise keep one poll waiting on the server
+        if in_flight.len() < MAX_IN_FLIGHT && (has_outbound || (streams_open && in_flight.is_empty())) {
+            let frames = endpoint.take_frames();
+            let wait = in_flight.is_empty() && !frames.iter().any(Frame::is_payload);
+            let message = ClientMessage::Mux { connection, frames, wait, resync };
+            resync = false;
+
+            let channel = channel.clone();
+            in_flight.spawn(async move { channel.send(&message).await });
+            continue;
+        }
+
+        tokio::select! {
+            Some(result) = in_flight.join_next() => match result {
+                Ok(Ok(ServerMessage::Mux { frames })) => {
+                    // Clients never accept streams, and datagrams for finished associations are dropped
+                    let _ = endpoint.receive(frames);
+                }
+                Ok(Ok(other)) => println!("❌ Unexpected {} from proxy server", other.name()),
+                Ok(Err(e)) => {
+                    println!("❌ Exchange with proxy server failed: {}", e);
+                    resync = true;
+                    sleep(Duration::from_millis(RETRY_DELAY)).await;
+                }
+                Err(_) => {}
+            },
+            _ = endpoint.outbound_ready() => {}
+            _ = sleep(Duration::from_millis(RETRANSMIT_CHECK)), if streams_open => {}
+        }
+    }
+}
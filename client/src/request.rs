@@ -0,0 +1,392 @@
+use std::fmt;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const READ_CHUNK: usize = 8 * 1024; // Bytes read from the browser at a time
+
+/// How much a browser may send in one request
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub max_header_bytes: usize,    // Request line, headers and chunked trailers together
+    pub max_headers: usize,
+    pub max_body_bytes: usize,
+}
+
+/// A request read off the local listener, with any chunked framing removed
+pub struct HttpRequest {
+    pub method: String,
+    pub target: String,
+    pub minor_version: u8,          // 0 for HTTP/1.0, 1 for HTTP/1.1
+    pub headers: Vec<(String, Vec<u8>)>, // In the order sent; values are raw bytes
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// The first value of a header, if it is there and readable as text
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .and_then(|(_, v)| std::str::from_utf8(v).ok())
+    }
+
+    /// Every value of a header, as text. Unreadable bytes are replaced, which makes
+    /// them invalid wherever a value has to follow a grammar.
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = std::borrow::Cow<'a, str>> {
+        self.headers
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| String::from_utf8_lossy(v))
+    }
+}
+
+/// Why a request could not be read
+pub enum ReadError {
+    Closed,                         // The browser closed the connection before sending anything
+    Io(String),
+    BadRequest(String),             // 400
+    BodyTooLarge,                   // 413
+    HeadersTooLarge,                // 431
+}
+
+impl ReadError {
+    /// The response to send the browser, if it is still there to read one
+    pub fn response(&self) -> Option<Vec<u8>> {
+        let (status, reason) = match self {
+            ReadError::Closed | ReadError::Io(_) => return None,
+            ReadError::BadRequest(_) => (400, "Bad Request"),
+            ReadError::BodyTooLarge => (413, "Content Too Large"),
+            ReadError::HeadersTooLarge => (431, "Request Header Fields Too Large"),
+        };
+        let body = format!("{} {}\n", status, reason);
+        Some(
+            format!(
+                "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                reason,
+                body.len(),
+                body
+            )
+            .into_bytes(),
+        )
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Closed => write!(f, "Connection closed"),
+            ReadError::Io(e) => write!(f, "Error reading from socket: {}", e),
+            ReadError::BadRequest(e) => write!(f, "Malformed request: {}", e),
+            ReadError::BodyTooLarge => write!(f, "Request body exceeds the size limit"),
+            ReadError::HeadersTooLarge => write!(f, "Request headers exceed the size limit"),
+        }
+    }
+}
+
+/// Reads one complete request. `buffer` holds bytes already received from the
+/// connection; whatever follows the request is left in it.
+pub async fn read_request<S>(stream: &mut S, buffer: &mut Vec<u8>, limits: &Limits) -> Result<HttpRequest, ReadError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Keep reading until the header block is complete
+    let (mut request, header_len) = loop {
+        let mut headers = vec![httparse::EMPTY_HEADER; limits.max_headers];
+        let mut parsed = httparse::Request::new(&mut headers);
+
+        match parsed.parse(buffer) {
+            Ok(httparse::Status::Complete(len)) => {
+                let request = HttpRequest {
+                    method: parsed.method.unwrap_or_default().to_string(),
+                    target: parsed.path.unwrap_or_default().to_string(),
+                    minor_version: parsed.version.unwrap_or(1),
+                    headers: parsed
+                        .headers
+                        .iter()
+                        .map(|h| (h.name.to_string(), h.value.to_vec()))
+                        .collect(),
+                    body: Vec::new(),
+                };
+                break (request, len);
+            }
+            Ok(httparse::Status::Partial) => {
+                if buffer.len() > limits.max_header_bytes {
+                    return Err(ReadError::HeadersTooLarge);
+                }
+            }
+            Err(httparse::Error::TooManyHeaders) => return Err(ReadError::HeadersTooLarge),
+            Err(e) => return Err(ReadError::BadRequest(e.to_string())),
+        }
+
+        if fill(stream, buffer).await? == 0 {
+            return Err(if buffer.is_empty() {
+                ReadError::Closed
+            } else {
+                ReadError::BadRequest("connection closed mid-request".to_string())
+            });
+        }
+    };
+
+    if header_len > limits.max_header_bytes {
+        return Err(ReadError::HeadersTooLarge);
+    }
+    buffer.drain(..header_len);
+
+    let framing = body_framing(&request)?;
+    if matches!(framing, Framing::Length(0)) || request.method.eq_ignore_ascii_case("CONNECT") {
+        return Ok(request);
+    }
+
+    if let Framing::Length(length) = framing {
+        if length > limits.max_body_bytes {
+            return Err(ReadError::BodyTooLarge);
+        }
+    }
+
+    // The browser is holding the body back until we say it is welcome
+    let expects_continue = request
+        .header("expect")
+        .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"));
+    if expects_continue && request.minor_version >= 1 && buffer.is_empty() {
+        stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await
+            .map_err(|e| ReadError::Io(e.to_string()))?;
+    }
+
+    request.body = match framing {
+        Framing::Length(length) => read_exact(stream, buffer, length).await?,
+        Framing::Chunked => read_chunked(stream, buffer, limits).await?,
+    };
+    Ok(request)
+}
+
+enum Framing {
+    Length(usize),
+    Chunked,
+}
+
+/// Works out how the body is delimited (RFC 9112 section 6.3)
+fn body_framing(request: &HttpRequest) -> Result<Framing, ReadError> {
+    let bad = |message: &str| ReadError::BadRequest(message.to_string());
+
+    let encodings: Vec<String> = request
+        .header_values("transfer-encoding")
+        .flat_map(|v| v.split(',').map(|e| e.trim().to_string()).collect::<Vec<_>>())
+        .filter(|v| !v.is_empty())
+        .collect();
+    if let Some(last) = encodings.last() {
+        if request.header_values("content-length").next().is_some() {
+            return Err(bad("both Transfer-Encoding and Content-Length present"));
+        }
+        if !last.eq_ignore_ascii_case("chunked") {
+            return Err(bad("request body length cannot be determined"));
+        }
+        return Ok(Framing::Chunked);
+    }
+
+    let mut length = None;
+    for value in request.header_values("content-length") {
+        for part in value.split(',').map(str::trim) {
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(bad("invalid Content-Length"));
+            }
+            // Too large to represent is certainly too large to accept
+            let parsed = part.parse::<usize>().map_err(|_| ReadError::BodyTooLarge)?;
+            if length.is_some_and(|l| l != parsed) {
+                return Err(bad("conflicting Content-Length values"));
+            }
+            length = Some(parsed);
+        }
+    }
+    Ok(Framing::Length(length.unwrap_or(0)))
+}
+
+async fn read_exact<S>(stream: &mut S, buffer: &mut Vec<u8>, length: usize) -> Result<Vec<u8>, ReadError>
+where
+    S: AsyncRead + Unpin,
+{
+    while buffer.len() < length {
+        if fill(stream, buffer).await? == 0 {
+            return Err(ReadError::BadRequest("body shorter than Content-Length".to_string()));
+        }
+    }
+    Ok(buffer.drain(..length).collect())
+}
+
+async fn read_chunked<S>(stream: &mut S, buffer: &mut Vec<u8>, limits: &Limits) -> Result<Vec<u8>, ReadError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut body = Vec::new();
+
+    loop {
+        let (start, size) = loop {
+            match httparse::parse_chunk_size(buffer) {
+                Ok(httparse::Status::Complete(parsed)) => break parsed,
+                Ok(httparse::Status::Partial) => {
+                    if buffer.len() > limits.max_header_bytes {
+                        return Err(ReadError::BadRequest("chunk size line too long".to_string()));
+                    }
+                }
+                Err(_) => return Err(ReadError::BadRequest("invalid chunk size".to_string())),
+            }
+            if fill(stream, buffer).await? == 0 {
+                return Err(ReadError::BadRequest("connection closed mid-chunk".to_string()));
+            }
+        };
+        buffer.drain(..start);
+
+        if size == 0 {
+            break;
+        }
+        // The size is the browser's to choose, so it is checked before any arithmetic
+        let size = usize::try_from(size).map_err(|_| ReadError::BodyTooLarge)?;
+        if size > limits.max_body_bytes.saturating_sub(body.len()) {
+            return Err(ReadError::BodyTooLarge);
+        }
+        let framed = size.checked_add(2).ok_or(ReadError::BodyTooLarge)?;
+
+        let mut chunk = read_exact(stream, buffer, framed).await?;
+        if !chunk.ends_with(b"\r\n") {
+            return Err(ReadError::BadRequest("chunk not terminated by CRLF".to_string()));
+        }
+        chunk.truncate(size);
+        body.extend_from_slice(&chunk);
+    }
+
+    // Skip trailer fields up to the blank line that ends the message
+    let mut trailer_bytes = 0;
+    loop {
+        if let Some(end) = buffer.windows(2).position(|w| w == b"\r\n") {
+            let blank = end == 0;
+            buffer.drain(..end + 2);
+            trailer_bytes += end + 2;
+            if trailer_bytes > limits.max_header_bytes {
+                return Err(ReadError::HeadersTooLarge);
+            }
+            if blank {
+                return Ok(body);
+            }
+            continue;
+        }
+        if buffer.len() > limits.max_header_bytes {
+            return Err(ReadError::HeadersTooLarge);
+        }
+        if fill(stream, buffer).await? == 0 {
+            return Err(ReadError::BadRequest("connection closed in trailers".to_string()));
+        }
+    }
+}
+
+/// Appends whatever the browser sends next to `buffer`, returning how much arrived
+async fn fill<S>(stream: &mut S, buffer: &mut Vec<u8>) -> Result<usize, ReadError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut chunk = [0; READ_CHUNK];
+    let n = stream.read(&mut chunk).await.map_err(|e| ReadError::Io(e.to_string()))?;
+    buffer.extend_from_slice(&chunk[..n]);
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        max_header_bytes: 1024,
+        max_headers: 16,
+        max_body_bytes: 64,
+    };
+
+    /// Reads a request from a connection that sends `input` and then closes,
+    /// returning it along with whatever was left unread
+    async fn read(input: &[u8]) -> (Result<HttpRequest, ReadError>, Vec<u8>) {
+        let (mut browser, mut listener) = tokio::io::duplex(64 * 1024);
+        browser.write_all(input).await.unwrap();
+        drop(browser);
+        let mut buffer = Vec::new();
+        let result = read_request(&mut listener, &mut buffer, &LIMITS).await;
+        (result, buffer)
+    }
+
+    async fn body(input: &[u8]) -> Vec<u8> {
+        match read(input).await.0 {
+            Ok(request) => request.body,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    async fn error(input: &[u8]) -> ReadError {
+        match read(input).await.0 {
+            Ok(_) => panic!("Request was accepted"),
+            Err(e) => e,
+        }
+    }
+
+    const CHUNKED: &str = "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    #[tokio::test]
+    async fn chunked_bodies_are_joined() {
+        let input = format!("{}5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nTrailer: x\r\n\r\nGET", CHUNKED);
+        let (result, rest) = read(input.as_bytes()).await;
+        assert_eq!(result.ok().map(|request| request.body), Some(b"hello world".to_vec()));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn chunked_body_errors() {
+        let cases = [
+            "zz\r\nhello\r\n0\r\n\r\n",   // Size is not hex
+            "5\r\nhelloXX0\r\n\r\n",      // Chunk not followed by CRLF
+            "5\r\nhel",                   // Closed mid-chunk
+            "5\r\nhello\r\n",             // Closed before the last chunk
+            "0\r\nTrailer: x\r\n",        // Closed in the trailers
+        ];
+        for case in cases {
+            let e = error(format!("{}{}", CHUNKED, case).as_bytes()).await;
+            assert!(matches!(e, ReadError::BadRequest(_)), "{:?}: {}", case, e);
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies_are_held_to_the_limits() {
+        let e = error(format!("{}41\r\n{}\r\n0\r\n\r\n", CHUNKED, "x".repeat(65)).as_bytes()).await;
+        assert!(matches!(e, ReadError::BodyTooLarge));
+        let e = error(format!("{}ffffffffffffffff\r\n", CHUNKED).as_bytes()).await;
+        assert!(matches!(e, ReadError::BodyTooLarge));
+        // A huge size after some body must not overflow the running total
+        let e = error(format!("{}5\r\nhello\r\nffffffffffffffff\r\n", CHUNKED).as_bytes()).await;
+        assert!(matches!(e, ReadError::BodyTooLarge));
+        let e = error(format!("{}5\r\nhello\r\nfffffffffffffffc\r\n", CHUNKED).as_bytes()).await;
+        assert!(matches!(e, ReadError::BodyTooLarge));
+        let e = error(format!("{}0\r\nTrailer: {}\r\n\r\n", CHUNKED, "x".repeat(2000)).as_bytes()).await;
+        assert!(matches!(e, ReadError::HeadersTooLarge));
+    }
+
+    #[tokio::test]
+    async fn lengths_are_checked() {
+        assert_eq!(body(b"POST / HTTP/1.1\r\nContent-Length: 3, 3\r\n\r\nabc").await, b"abc");
+        let cases: [&[u8]; 4] = [
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd",
+            b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+        ];
+        for case in cases {
+            assert!(matches!(error(case).await, ReadError::BadRequest(_)));
+        }
+        let e = error(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nabc").await;
+        assert!(matches!(e, ReadError::BadRequest(_)));
+        let e = error(b"POST / HTTP/1.1\r\nContent-Length: 65\r\n\r\n").await;
+        assert!(matches!(e, ReadError::BodyTooLarge));
+    }
+
+    #[tokio::test]
+    async fn empty_connections_are_closed_quietly() {
+        assert!(matches!(error(b"").await, ReadError::Closed));
+        assert!(matches!(error(b"GET / HT").await, ReadError::BadRequest(_)));
+    }
+}
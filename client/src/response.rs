@@ -0,0 +1,167 @@
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::channel::BodyPieces;
+use crate::request::HttpRequest;
+
+// Headers that describe the upstream connection rather than the response. The
+// listener frames every response itself, so these are never relayed.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+/// A response to relay to the browser
+pub struct Response {
+    pub status: u16,
+    pub reason: Option<String>, // Reason phrase from the origin; the standard one is used if absent
+    pub headers: Vec<(String, Vec<u8>)>, // In the order the origin sent them
+    pub body: Vec<u8>,                   // The whole body, or its first piece if more is to come
+    pub rest: Option<BodyPieces>,        // The rest of the body, still held by the server
+}
+
+impl Response {
+    /// An empty response carrying only a status
+    pub fn status(status: u16) -> Self {
+        Response {
+            status,
+            reason: None,
+            headers: Vec::new(),
+            body: Vec::new(),
+            rest: None,
+        }
+    }
+}
+
+/// Whether the browser wants the connection kept open after this request
+pub fn keep_alive(request: &HttpRequest) -> bool {
+    let mut tokens = request
+        .header_values("connection")
+        .chain(request.header_values("proxy-connection"))
+        .flat_map(|v| v.split(',').map(|t| t.trim().to_ascii_lowercase()).collect::<Vec<_>>());
+
+    if request.minor_version >= 1 {
+        !tokens.any(|t| t == "close")
+    } else {
+        tokens.any(|t| t == "keep-alive")
+    }
+}
+
+/// Writes a complete response with framing the browser can rely on to find where
+/// it ends. A body still held by the server is relayed as it arrives. Returns
+/// whether the connection can safely carry the next request.
+pub async fn write_response<S>(
+    stream: &mut S,
+    request: &HttpRequest,
+    response: Response,
+    keep_alive: bool,
+) -> std::io::Result<bool>
+where
+    S: AsyncWrite + Unpin,
+{
+    let Response { status, reason, headers, body, rest } = response;
+
+    // A reason that could break the status line is replaced by the standard one
+    let reason = reason
+        .as_deref()
+        .filter(|r| r.bytes().all(|b| b == b'\t' || (b' '..=b'~').contains(&b)))
+        .or_else(|| http::StatusCode::from_u16(status).ok().and_then(|s| s.canonical_reason()))
+        .unwrap_or("");
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason).into_bytes();
+
+    // Values are written byte for byte, repeats and order included, unless a line
+    // break in one would let it inject headers of its own
+    for (name, value) in &headers {
+        let injects = value.iter().any(|&b| b == b'\r' || b == b'\n');
+        if !injects && !HOP_BY_HOP.contains(&name.to_ascii_lowercase().as_str()) {
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.extend_from_slice(b"\r\n");
+        }
+    }
+
+    // 1xx and 204 never have a body; HEAD and 304 describe one they do not send
+    let no_length = status < 200 || status == 204;
+    let bodiless = no_length || status == 304 || request.method.eq_ignore_ascii_case("HEAD");
+    let upstream_length = upstream_length(&headers);
+
+    // A body still to come is delimited by the length the origin gave, by chunked
+    // encoding, or failing both by closing the connection
+    let streamed = !bodiless && rest.is_some();
+    let chunked = streamed && upstream_length.is_none() && request.minor_version >= 1;
+    let keep_alive = keep_alive && !(streamed && upstream_length.is_none() && !chunked);
+    if bodiless {
+        if let Some(length) = upstream_length.filter(|_| !no_length) {
+            head.extend_from_slice(format!("Content-Length: {}\r\n", length).as_bytes());
+        }
+    } else if !streamed {
+        head.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+    } else if let Some(length) = upstream_length {
+        head.extend_from_slice(format!("Content-Length: {}\r\n", length).as_bytes());
+    } else if chunked {
+        head.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+    }
+
+    if !keep_alive {
+        head.extend_from_slice(b"Connection: close\r\n");
+    } else if request.minor_version == 0 {
+        head.extend_from_slice(b"Connection: keep-alive\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+
+    stream.write_all(&head).await?;
+    match rest {
+        _ if bodiless => {}
+        None => stream.write_all(&body).await?,
+        Some(mut rest) => {
+            let mut piece = body;
+            let mut sent = 0;
+            loop {
+                sent += piece.len() as u64;
+                if upstream_length.is_some_and(|length| sent > length) {
+                    return Err(std::io::Error::other("Response body is longer than its Content-Length"));
+                }
+                if chunked && !piece.is_empty() {
+                    stream.write_all(format!("{:x}\r\n", piece.len()).as_bytes()).await?;
+                    stream.write_all(&piece).await?;
+                    stream.write_all(b"\r\n").await?;
+                } else {
+                    stream.write_all(&piece).await?;
+                }
+                // The browser sees each piece as soon as it arrives
+                stream.flush().await?;
+
+                piece = match rest.next().await {
+                    Ok(Some(piece)) => piece,
+                    Ok(None) => break,
+                    Err(e) => return Err(std::io::Error::other(e)),
+                };
+            }
+
+            if upstream_length.is_some_and(|length| sent < length) {
+                return Err(std::io::Error::other("Response body is shorter than its Content-Length"));
+            }
+            if chunked {
+                stream.write_all(b"0\r\n\r\n").await?;
+            }
+        }
+    }
+    stream.flush().await?;
+    Ok(keep_alive)
+}
+
+/// The Content-Length the origin sent, if it sent a usable one
+fn upstream_length(headers: &[(String, Vec<u8>)]) -> Option<u64> {
+    let (_, length) = headers.iter().find(|(n, _)| n.eq_ignore_ascii_case("content-length"))?;
+    let length = std::str::from_utf8(length).ok()?;
+    if !length.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    length.parse().ok()
+}
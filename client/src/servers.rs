@@ -0,0 +1,209 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use reqwest::Url;
+use tokio::time::{Duration, Instant};
+
+const RETRY_AFTER_MIN: u64 = 5; // Seconds a failed server is left alone before it is tried again
+const RETRY_AFTER_MAX: u64 = 300; // The wait doubles with each consecutive failure up to this
+const LATENCY_WEIGHT: f64 = 0.3; // How much each new round-trip moves the latency average
+
+/// Where one masquerade server is reached, given on the command line as
+/// `URL[;host=NAME]`, e.g. `https://203.0.113.7:8443/app;host=cdn.example.com`
+#[derive(Clone)]
+pub struct Server {
+    pub base: String,         // Scheme, authority and path prefix, without a trailing slash
+    pub host: Option<String>, // Host header to send instead of the one in the URL
+}
+
+impl FromStr for Server {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (url, options) = match s.split_once(';') {
+            Some((url, options)) => (url, Some(options)),
+            None => (s, None),
+        };
+
+        let host = match options {
+            None => None,
+            Some(options) => match options.trim().strip_prefix("host=") {
+                Some(host) if !host.is_empty() => Some(host.to_string()),
+                _ => return Err(format!("Unknown server option: {} (expected host=NAME)", options)),
+            },
+        };
+
+        let parsed = Url::parse(url.trim()).map_err(|e| format!("Invalid server URL {}: {}", url, e))?;
+        if parsed.scheme() != "http" && parsed.scheme() != "https" {
+            return Err(format!("Server URL {} must use http or https", url));
+        }
+        if parsed.host_str().is_none() {
+            return Err(format!("Server URL {} has no host", url));
+        }
+        if parsed.query().is_some() || parsed.fragment().is_some() {
+            return Err(format!("Server URL {} cannot have a query or fragment", url));
+        }
+
+        // Carrier paths start with a slash, so the prefix must not end with one
+        let base = parsed.as_str().trim_end_matches('/').to_string();
+        Ok(Server { base, host })
+    }
+}
+
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.host {
+            Some(host) => write!(f, "{} (as {})", self.base, host),
+            None => f.write_str(&self.base),
+        }
+    }
+}
+
+/// How requests are spread over several servers
+#[derive(Clone, Copy, PartialEq)]
+pub enum Selection {
+    Failover,   // Always the first healthy server in the order given
+    RoundRobin, // Take turns between healthy servers
+    Latency,    // The healthy server with the fastest recent round-trips
+}
+
+impl FromStr for Selection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "failover" => Ok(Selection::Failover),
+            "round-robin" => Ok(Selection::RoundRobin),
+            "latency" => Ok(Selection::Latency),
+            _ => Err(format!("Unknown selection: {} (expected failover, round-robin or latency)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Selection::Failover => "failover",
+            Selection::RoundRobin => "round-robin",
+            Selection::Latency => "latency",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What we have recently seen of one server
+#[derive(Default)]
+struct Health {
+    failures: u32,             // Consecutive failed exchanges
+    retry_at: Option<Instant>, // Not tried again before this, unless every server is down
+    latency: Option<f64>,      // Moving average of round-trips, in milliseconds
+}
+
+impl Health {
+    fn is_up(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|at| now >= at)
+    }
+}
+
+/// The configured servers and their health
+pub struct Servers {
+    servers: Vec<Server>,
+    selection: Selection,
+    health: Mutex<Vec<Health>>,
+    next: Mutex<usize>, // Round-robin position
+}
+
+impl Servers {
+    pub fn new(servers: Vec<Server>, selection: Selection) -> Result<Self, String> {
+        if servers.is_empty() {
+            return Err("At least one server is required".to_string());
+        }
+        Ok(Servers {
+            health: Mutex::new(servers.iter().map(|_| Health::default()).collect()),
+            servers,
+            selection,
+            next: Mutex::new(0),
+        })
+    }
+
+    pub fn get(&self, index: usize) -> &Server {
+        &self.servers[index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    /// Every server in the order they should be tried for the next exchange.
+    /// Servers that recently failed go last, soonest to be retried first.
+    pub fn order(&self) -> Vec<usize> {
+        let now = Instant::now();
+        let health = self.health.lock().unwrap();
+        let mut order: Vec<usize> = (0..self.servers.len()).collect();
+
+        match self.selection {
+            Selection::Failover => {}
+            Selection::RoundRobin => {
+                let mut next = self.next.lock().unwrap();
+                order.rotate_left(*next % self.servers.len());
+                *next = next.wrapping_add(1);
+            }
+            // Unmeasured servers sort first so that each gets measured
+            Selection::Latency => order.sort_by(|&a, &b| {
+                let a = health[a].latency.unwrap_or(0.0);
+                let b = health[b].latency.unwrap_or(0.0);
+                a.total_cmp(&b)
+            }),
+        }
+
+        // A stable sort keeps the policy's order within the healthy and the failed servers
+        order.sort_by_key(|&i| match health[i].retry_at {
+            Some(at) if at > now => (1, Some(at)),
+            _ => (0, None),
+        });
+        order
+    }
+
+    /// The preferred server that is currently up, for traffic that should stay put
+    pub fn preferred(&self) -> usize {
+        self.order()[0]
+    }
+
+    /// Whether a server is up (or due to be retried)
+    pub fn is_up(&self, index: usize) -> bool {
+        self.health.lock().unwrap()[index].is_up(Instant::now())
+    }
+
+    /// Records a successful exchange and, if given, how long the round-trip took
+    pub fn succeeded(&self, index: usize, round_trip: Option<Duration>) {
+        let mut health = self.health.lock().unwrap();
+        let health = &mut health[index];
+        if health.failures > 0 {
+            println!("💚 Server {} is reachable again", self.servers[index]);
+        }
+        health.failures = 0;
+        health.retry_at = None;
+
+        if let Some(round_trip) = round_trip {
+            let sample = round_trip.as_secs_f64() * 1000.0;
+            health.latency = Some(match health.latency {
+                Some(average) => average + LATENCY_WEIGHT * (sample - average),
+                None => sample,
+            });
+        }
+    }
+
+    /// Records a failed exchange and takes the server out of rotation for a while
+    pub fn failed(&self, index: usize, error: &str) {
+        let mut health = self.health.lock().unwrap();
+        let health = &mut health[index];
+        health.failures += 1;
+
+        let wait = RETRY_AFTER_MIN
+            .saturating_mul(1 << (health.failures - 1).min(16))
+            .min(RETRY_AFTER_MAX);
+        health.retry_at = Some(Instant::now() + Duration::from_secs(wait));
+        println!("💔 Server {} failed ({}), retrying it in {}s", self.servers[index], error, wait);
+    }
+}
@@ -0,0 +1,240 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{timeout, Duration};
+
+use common::structs::DENIED;
+
+use crate::mux::Mux;
+use crate::{tunnel, udp};
+
+pub const VERSION: u8 = 0x05;
+const HANDSHAKE_TIMEOUT: u64 = 30; // Seconds a client has to finish negotiating
+
+// Authentication methods (RFC 1928 section 3)
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE: u8 = 0xFF;
+
+// Commands
+const CONNECT: u8 = 0x01;
+const UDP_ASSOCIATE: u8 = 0x03;
+
+// Address types
+pub const IPV4: u8 = 0x01;
+pub const DOMAIN: u8 = 0x03;
+pub const IPV6: u8 = 0x04;
+
+// Reply codes (RFC 1928 section 6)
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const NOT_ALLOWED: u8 = 0x02;
+const NETWORK_UNREACHABLE: u8 = 0x03;
+const HOST_UNREACHABLE: u8 = 0x04;
+const CONNECTION_REFUSED: u8 = 0x05;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Username and password SOCKS clients must present (RFC 1929)
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// What a SOCKS client asked for
+enum Command {
+    Connect(String), // host:port
+    UdpAssociate,
+}
+
+/// Serves one SOCKS5 connection. CONNECT requests become multiplexed streams the
+/// server dials, so domain names are resolved on the server side; UDP ASSOCIATE
+/// relays datagrams through the server for as long as the connection stays open.
+pub async fn handle_socks(mut stream: TcpStream, mux: &Mux, credentials: Option<&Credentials>) {
+    let negotiated = timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), negotiate(&mut stream, credentials)).await;
+    let target = match negotiated {
+        Ok(Ok(Command::Connect(target))) => target,
+        Ok(Ok(Command::UdpAssociate)) => return associate(stream, mux).await,
+        Ok(Err(e)) => {
+            println!("❌ SOCKS5 negotiation failed: {}", e);
+            return;
+        }
+        Err(_) => {
+            println!("❌ SOCKS5 negotiation timed out");
+            return;
+        }
+    };
+
+    println!("🧦 SOCKS5 CONNECT request for: {}", target);
+    let id = match mux.open(&target).await {
+        Ok(id) => id,
+        Err(e) => {
+            println!("❌ Server could not open tunnel: {}", e);
+            let _ = reply(&mut stream, failure_code(&e), None).await;
+            return;
+        }
+    };
+
+    if reply(&mut stream, SUCCEEDED, None).await.is_err() {
+        return;
+    }
+    tunnel::relay(mux, id, stream).await;
+}
+
+/// Opens a local UDP relay port for the client and serves it until the client hangs up
+async fn associate(mut stream: TcpStream, mux: &Mux) {
+    // Bind where the client reached us, so the relay is reachable the same way
+    let local_ip = stream.local_addr().map(|a| a.ip()).unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let socket = match UdpSocket::bind(SocketAddr::new(local_ip, 0)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("❌ Failed to bind UDP relay: {}", e);
+            let _ = reply(&mut stream, GENERAL_FAILURE, None).await;
+            return;
+        }
+    };
+
+    let bound = socket.local_addr().ok();
+    println!("📡 SOCKS5 UDP ASSOCIATE relaying on {:?}", bound);
+    if reply(&mut stream, SUCCEEDED, bound).await.is_err() {
+        return;
+    }
+    udp::relay(mux, socket, stream).await;
+}
+
+/// Runs method selection, authentication and the request
+async fn negotiate(stream: &mut TcpStream, credentials: Option<&Credentials>) -> Result<Command, String> {
+    let version = stream.read_u8().await.map_err(|e| e.to_string())?;
+    if version != VERSION {
+        return Err(format!("Unsupported SOCKS version {}", version));
+    }
+    let count = stream.read_u8().await.map_err(|e| e.to_string())?;
+    let mut methods = vec![0; count as usize];
+    stream.read_exact(&mut methods).await.map_err(|e| e.to_string())?;
+
+    let wanted = if credentials.is_some() { USER_PASS } else { NO_AUTH };
+    if !methods.contains(&wanted) {
+        let _ = stream.write_all(&[VERSION, NO_ACCEPTABLE]).await;
+        return Err("No acceptable authentication method offered".to_string());
+    }
+    stream.write_all(&[VERSION, wanted]).await.map_err(|e| e.to_string())?;
+
+    if let Some(credentials) = credentials {
+        authenticate(stream, credentials).await?;
+    }
+
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await.map_err(|e| e.to_string())?;
+    let [version, command, _, address_type] = header;
+    if version != VERSION {
+        return Err(format!("Unsupported SOCKS version {}", version));
+    }
+
+    let host = match address_type {
+        IPV4 => {
+            let mut octets = [0; 4];
+            stream.read_exact(&mut octets).await.map_err(|e| e.to_string())?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        DOMAIN => {
+            let len = stream.read_u8().await.map_err(|e| e.to_string())?;
+            let mut name = vec![0; len as usize];
+            stream.read_exact(&mut name).await.map_err(|e| e.to_string())?;
+            String::from_utf8(name).map_err(|_| "Domain name is not valid UTF-8".to_string())?
+        }
+        IPV6 => {
+            let mut octets = [0; 16];
+            stream.read_exact(&mut octets).await.map_err(|e| e.to_string())?;
+            format!("[{}]", Ipv6Addr::from(octets))
+        }
+        _ => {
+            let _ = reply(stream, ADDRESS_NOT_SUPPORTED, None).await;
+            return Err(format!("Unsupported address type {}", address_type));
+        }
+    };
+    let port = stream.read_u16().await.map_err(|e| e.to_string())?;
+
+    match command {
+        CONNECT => Ok(Command::Connect(format!("{}:{}", host, port))),
+        // The address is where the client will send from, which we learn from its first datagram
+        UDP_ASSOCIATE => Ok(Command::UdpAssociate),
+        _ => {
+            let _ = reply(stream, COMMAND_NOT_SUPPORTED, None).await;
+            Err(format!("Unsupported command {}", command))
+        }
+    }
+}
+
+/// Username/password sub-negotiation (RFC 1929)
+async fn authenticate(stream: &mut TcpStream, credentials: &Credentials) -> Result<(), String> {
+    let version = stream.read_u8().await.map_err(|e| e.to_string())?;
+    if version != 0x01 {
+        return Err(format!("Unsupported authentication version {}", version));
+    }
+
+    let len = stream.read_u8().await.map_err(|e| e.to_string())?;
+    let mut username = vec![0; len as usize];
+    stream.read_exact(&mut username).await.map_err(|e| e.to_string())?;
+    let len = stream.read_u8().await.map_err(|e| e.to_string())?;
+    let mut password = vec![0; len as usize];
+    stream.read_exact(&mut password).await.map_err(|e| e.to_string())?;
+
+    // Both are always checked, so the time taken does not say which was wrong
+    let accepted = matches(&username, &credentials.username) & matches(&password, &credentials.password);
+    stream
+        .write_all(&[0x01, if accepted { 0x00 } else { 0x01 }])
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if accepted {
+        Ok(())
+    } else {
+        Err("Invalid username or password".to_string())
+    }
+}
+
+/// Compares digests rather than the credentials themselves, in constant time, so
+/// the time taken reveals neither how much of one matched nor how long it is
+fn matches(given: &[u8], expected: &str) -> bool {
+    Sha256::digest(given).ct_eq(&Sha256::digest(expected.as_bytes())).into()
+}
+
+/// Sends a reply. Without a `bound` address it is left unspecified, since for
+/// CONNECT the real one is on the server.
+async fn reply(stream: &mut TcpStream, code: u8, bound: Option<SocketAddr>) -> std::io::Result<()> {
+    let mut message = vec![VERSION, code, 0x00];
+    match bound.map(|a| (a.ip(), a.port())) {
+        Some((IpAddr::V6(ip), port)) => {
+            message.push(IPV6);
+            message.extend_from_slice(&ip.octets());
+            message.extend_from_slice(&port.to_be_bytes());
+        }
+        Some((IpAddr::V4(ip), port)) => {
+            message.push(IPV4);
+            message.extend_from_slice(&ip.octets());
+            message.extend_from_slice(&port.to_be_bytes());
+        }
+        None => message.extend_from_slice(&[IPV4, 0, 0, 0, 0, 0, 0]),
+    }
+    stream.write_all(&message).await
+}
+
+/// Picks the reply code that best matches why the server could not connect
+fn failure_code(error: &str) -> u8 {
+    if error.starts_with(DENIED) {
+        return NOT_ALLOWED;
+    }
+    let error = error.to_ascii_lowercase();
+    if error.contains("refused") {
+        CONNECTION_REFUSED
+    } else if error.contains("network is unreachable") {
+        NETWORK_UNREACHABLE
+    } else if error.contains("timed out") || error.contains("unreachable") || error.contains("lookup") {
+        HOST_UNREACHABLE
+    } else {
+        GENERAL_FAILURE
+    }
+}
@@ -0,0 +1,44 @@
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use common::structs::DENIED;
+
+use crate::mux::Mux;
+
+/// Carries a CONNECT tunnel as a multiplexed stream. The server dials the
+/// target; once it confirms, the browser's socket is attached to the stream.
+pub async fn handle_connect(mut client_stream: TcpStream, addr: &str, mux: &Mux) {
+    println!("🔐 Establishing HTTPS tunnel to {} via proxy server", addr);
+
+    let stream = match mux.open(addr).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("❌ Server could not open tunnel: {}", e);
+            let response: &[u8] = if e.starts_with(DENIED) {
+                b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n"
+            } else {
+                b"HTTP/1.1 502 Bad Gateway\r\n\r\n"
+            };
+            let _ = client_stream.write_all(response).await;
+            return;
+        }
+    };
+
+    println!("✅ Tunnel opened by server");
+    let response = "HTTP/1.1 200 Connection Established\r\n\r\n";
+    if client_stream.write_all(response.as_bytes()).await.is_err() {
+        return;
+    }
+
+    relay(mux, stream, client_stream).await;
+}
+
+/// Shuttles bytes between an opened stream and the local socket until both close
+pub async fn relay(mux: &Mux, stream: u32, socket: TcpStream) {
+    println!("🔄 Starting bidirectional tunnel");
+    let (from_client, from_server) = mux.attach(stream, socket).await;
+
+    println!("🔄 Tunnel closed. Bytes transferred:");
+    println!("   Client → Server: {} bytes", from_client);
+    println!("   Server → Client: {} bytes", from_server);
+}
@@ -0,0 +1,107 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpStream, UdpSocket};
+
+use common::mux::MAX_DATAGRAM;
+
+use crate::mux::Mux;
+use crate::socks::{DOMAIN, IPV4, IPV6};
+
+/// Relays a SOCKS5 UDP association (RFC 1928 section 7) through the server until
+/// the client closes the TCP connection that requested it
+pub async fn relay(mux: &Mux, socket: UdpSocket, mut control: TcpStream) {
+    let (association, mut from_server) = mux.associate();
+    let client_ip = control.peer_addr().map(|a| a.ip()).ok();
+    let mut client: Option<SocketAddr> = None;
+
+    let mut buffer = vec![0; 64 * 1024];
+    let mut control_buffer = [0; 64];
+    let (mut sent, mut received) = (0usize, 0usize);
+
+    loop {
+        tokio::select! {
+            datagram = socket.recv_from(&mut buffer) => {
+                let Ok((n, source)) = datagram else {
+                    break;
+                };
+                // Only the client that asked for the association may use it
+                if Some(source.ip()) != client_ip {
+                    continue;
+                }
+                client = Some(source);
+
+                match decapsulate(&buffer[..n]) {
+                    Some((_, payload)) if payload.len() > MAX_DATAGRAM => {
+                        println!("⚠️ Dropped oversized UDP datagram ({} bytes)", payload.len());
+                    }
+                    Some((destination, payload)) => {
+                        mux.send_datagram(association, &destination, payload);
+                        sent += 1;
+                    }
+                    None => println!("⚠️ Dropped malformed or fragmented UDP datagram"),
+                }
+            }
+            Some((peer, data)) = from_server.recv() => {
+                let (Some(client), Ok(source)) = (client, peer.parse::<SocketAddr>()) else {
+                    continue;
+                };
+                let _ = socket.send_to(&encapsulate(source, &data), client).await;
+                received += 1;
+            }
+            read = control.read(&mut control_buffer) => {
+                if matches!(read, Ok(0) | Err(_)) {
+                    break;
+                }
+            }
+        }
+    }
+
+    mux.dissociate(association);
+    println!("📡 UDP association closed ({} datagrams out, {} back)", sent, received);
+}
+
+/// Splits a SOCKS UDP request header from its payload, returning the destination as host:port
+fn decapsulate(datagram: &[u8]) -> Option<(String, &[u8])> {
+    // RSV (2 bytes), FRAG, ATYP; fragments are not supported and are dropped
+    let (&[0, 0, 0, address_type], rest) = datagram.split_first_chunk::<4>()? else {
+        return None;
+    };
+
+    let (host, rest) = match address_type {
+        IPV4 => {
+            let (octets, rest) = rest.split_first_chunk::<4>()?;
+            (Ipv4Addr::from(*octets).to_string(), rest)
+        }
+        DOMAIN => {
+            let (&len, rest) = rest.split_first()?;
+            let (name, rest) = rest.split_at_checked(len as usize)?;
+            (String::from_utf8(name.to_vec()).ok()?, rest)
+        }
+        IPV6 => {
+            let (octets, rest) = rest.split_first_chunk::<16>()?;
+            (format!("[{}]", Ipv6Addr::from(*octets)), rest)
+        }
+        _ => return None,
+    };
+    let (port, payload) = rest.split_first_chunk::<2>()?;
+    Some((format!("{}:{}", host, u16::from_be_bytes(*port)), payload))
+}
+
+/// Prefixes a reply with the SOCKS UDP header naming where it came from
+fn encapsulate(source: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0, 0, 0];
+    match source.ip() {
+        IpAddr::V4(ip) => {
+            datagram.push(IPV4);
+            datagram.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            datagram.push(IPV6);
+            datagram.extend_from_slice(&ip.octets());
+        }
+    }
+    datagram.extend_from_slice(&source.port().to_be_bytes());
+    datagram.extend_from_slice(data);
+    datagram
+}
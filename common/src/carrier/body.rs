@@ -0,0 +1,37 @@
+use super::{expect_route, Carrier, CarrierRequest, CarrierResponse};
+
+const PATH: &str = "/api/v1/events";
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Carries the payload as the raw body of a POST request and its response,
+/// like a telemetry or analytics endpoint. Has no practical size limit.
+pub struct BodyCarrier;
+
+impl Carrier for BodyCarrier {
+    fn encode_request(&self, payload: &[u8]) -> Result<CarrierRequest, String> {
+        Ok(CarrierRequest {
+            method: "POST".to_string(),
+            path: PATH.to_string(),
+            query: None,
+            headers: vec![("content-type".to_string(), CONTENT_TYPE.to_string())],
+            body: payload.to_vec(),
+        })
+    }
+
+    fn decode_request(&self, request: &CarrierRequest) -> Result<Vec<u8>, String> {
+        expect_route(request, "POST", PATH)?;
+        Ok(request.body.clone())
+    }
+
+    fn encode_response(&self, payload: &[u8]) -> Result<CarrierResponse, String> {
+        Ok(CarrierResponse {
+            status: 200,
+            headers: vec![("content-type".to_string(), CONTENT_TYPE.to_string())],
+            body: payload.to_vec(),
+        })
+    }
+
+    fn decode_response(&self, response: &CarrierResponse) -> Result<Vec<u8>, String> {
+        Ok(response.body.clone())
+    }
+}
@@ -0,0 +1,65 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine as _};
+
+use super::{expect_route, json_envelope, open_json_envelope, Carrier, CarrierRequest, CarrierResponse};
+
+const PATH: &str = "/";
+const COOKIE_PREFIX: &str = "_sid";
+const CHUNK_SIZE: usize = 2048; // Keeps each cookie well under common per-cookie limits
+
+/// Carries the payload in a set of session-looking cookies on a GET request,
+/// so the request line itself stays clean.
+pub struct HeaderCarrier;
+
+impl Carrier for HeaderCarrier {
+    fn encode_request(&self, payload: &[u8]) -> Result<CarrierRequest, String> {
+        let encoded = BASE64_URL.encode(payload);
+        let cookie = encoded
+            .as_bytes()
+            .chunks(CHUNK_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| format!("{}{}={}", COOKIE_PREFIX, i, String::from_utf8_lossy(chunk)))
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        Ok(CarrierRequest {
+            method: "GET".to_string(),
+            path: PATH.to_string(),
+            query: None,
+            headers: vec![("cookie".to_string(), cookie)],
+            body: Vec::new(),
+        })
+    }
+
+    fn decode_request(&self, request: &CarrierRequest) -> Result<Vec<u8>, String> {
+        expect_route(request, "GET", PATH)?;
+
+        // Cookies may be split over several headers, so gather all of them first
+        let mut chunks: Vec<(usize, &str)> = request
+            .headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, v)| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .filter_map(|(name, value)| {
+                let index = name.strip_prefix(COOKIE_PREFIX)?.parse().ok()?;
+                Some((index, value))
+            })
+            .collect();
+
+        if chunks.is_empty() {
+            return Err("Missing payload cookies".to_string());
+        }
+
+        chunks.sort_by_key(|(index, _)| *index);
+        let encoded: String = chunks.into_iter().map(|(_, value)| value).collect();
+        BASE64_URL.decode(encoded).map_err(|e| e.to_string())
+    }
+
+    fn encode_response(&self, payload: &[u8]) -> Result<CarrierResponse, String> {
+        Ok(json_envelope(payload))
+    }
+
+    fn decode_response(&self, response: &CarrierResponse) -> Result<Vec<u8>, String> {
+        open_json_envelope(response)
+    }
+}
@@ -0,0 +1,297 @@
+use std::fs;
+use std::path::Path;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine as _};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use super::{expect_route, Carrier, CarrierRequest, CarrierResponse};
+
+const PATH: &str = "/blog/comments";
+const FIELD: &str = "comment";
+
+// Markers that surround the payload for each place it can be hidden in a page
+const SCRIPT_OPEN: &str = "window.__INITIAL_STATE__ = \"";
+const SCRIPT_CLOSE: &str = "\";";
+const ATTRIBUTE_OPEN: &str = "data-props=\"";
+const ATTRIBUTE_CLOSE: &str = "\"";
+const COMMENT_OPEN: &str = "<!-- page-cache: ";
+const COMMENT_CLOSE: &str = " -->";
+
+const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{{title}}</title>
+<link rel="stylesheet" href="/assets/css/main.css">
+{{head}}
+</head>
+<body>
+<header class="site-header"><a href="/">Notes from the Field</a></header>
+<main>
+<article class="post"{{article}}>
+<h1>{{title}}</h1>
+<p class="meta">By {{author}} &middot; {{date}}</p>
+{{content}}
+</article>
+</main>
+<footer class="site-footer">&copy; Notes from the Field</footer>
+{{footer}}
+</body>
+</html>
+"#;
+
+const TITLES: &[&str] = &[
+    "What I Learned From a Year of Sourdough",
+    "A Slow Weekend in the Lake District",
+    "Notes on Repairing an Old Road Bike",
+    "Why I Went Back to Paper Notebooks",
+    "The Quiet Joy of Container Gardening",
+    "Three Recipes for a Rainy Sunday",
+];
+
+const AUTHORS: &[&str] = &["Sam Porter", "Alex Reed", "Jamie Hollis", "Morgan Ellery"];
+
+const SENTENCES: &[&str] = &[
+    "It started, as these things usually do, with a small experiment that got out of hand.",
+    "I had no real plan beyond wanting to try something different for a while.",
+    "The first attempt was a disaster, but an instructive one.",
+    "Looking back, most of the effort went into the preparation rather than the work itself.",
+    "A friend suggested a simpler approach and, annoyingly, they were right.",
+    "There is something satisfying about doing a thing slowly and properly.",
+    "By the third week the routine had started to feel natural.",
+    "I kept a few notes along the way, which are tidied up below.",
+    "None of this is particularly original, but it worked well for me.",
+    "If you try it yourself, expect a few surprises along the way.",
+    "The weather did not cooperate, which turned out to be a blessing.",
+    "Most of the tools involved were things I already had lying around.",
+];
+
+/// Where in a page the payload is hidden
+#[derive(Clone, Copy)]
+enum Slot {
+    Script,    // A hydration-state script in the head
+    Attribute, // A data attribute on the article element
+    Comment,   // A cache comment at the end of the body
+}
+
+impl Slot {
+    fn placeholder(self) -> &'static str {
+        match self {
+            Slot::Script => "{{head}}",
+            Slot::Attribute => "{{article}}",
+            Slot::Comment => "{{footer}}",
+        }
+    }
+
+    fn markers(self) -> (&'static str, &'static str) {
+        match self {
+            Slot::Script => (SCRIPT_OPEN, SCRIPT_CLOSE),
+            Slot::Attribute => (ATTRIBUTE_OPEN, ATTRIBUTE_CLOSE),
+            Slot::Comment => (COMMENT_OPEN, COMMENT_CLOSE),
+        }
+    }
+
+    fn render(self, encoded: &str) -> String {
+        match self {
+            Slot::Script => format!("<script>{}{}{}</script>", SCRIPT_OPEN, encoded, SCRIPT_CLOSE),
+            Slot::Attribute => format!(" {}{}{}", ATTRIBUTE_OPEN, encoded, ATTRIBUTE_CLOSE),
+            Slot::Comment => format!("{}{}{}", COMMENT_OPEN, encoded, COMMENT_CLOSE),
+        }
+    }
+}
+
+const SLOTS: [Slot; 3] = [Slot::Script, Slot::Attribute, Slot::Comment];
+
+/// Disguises responses as blog articles rendered from HTML templates, and
+/// requests as comments posted to that blog.
+pub struct HtmlCarrier {
+    templates: Vec<String>,
+}
+
+impl HtmlCarrier {
+    /// Uses every `.html` file in `dir` as a page template
+    pub fn from_dir(dir: &Path) -> Result<Self, String> {
+        let entries = fs::read_dir(dir).map_err(|e| format!("Failed to read template directory {}: {}", dir.display(), e))?;
+
+        let mut templates = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("html")) {
+                continue;
+            }
+            let template = fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            if !SLOTS.iter().any(|slot| template.contains(slot.placeholder())) {
+                return Err(format!(
+                    "Template {} has none of the {{{{head}}}}, {{{{article}}}} or {{{{footer}}}} placeholders",
+                    path.display()
+                ));
+            }
+            templates.push(template);
+        }
+
+        if templates.is_empty() {
+            return Err(format!("No HTML templates found in {}", dir.display()));
+        }
+        Ok(HtmlCarrier { templates })
+    }
+
+    /// Uses the built-in article template
+    pub fn builtin() -> Self {
+        HtmlCarrier {
+            templates: vec![DEFAULT_TEMPLATE.to_string()],
+        }
+    }
+
+    /// Renders a random article from a random template with the payload hidden in it
+    fn render(&self, payload: &[u8]) -> String {
+        let mut rng = rand::thread_rng();
+        let template = self.templates.choose(&mut rng).expect("HtmlCarrier always has a template");
+
+        let slots: Vec<Slot> = SLOTS.iter().copied().filter(|slot| template.contains(slot.placeholder())).collect();
+        let chosen = *slots.choose(&mut rng).expect("Templates are checked for a payload placeholder");
+
+        let paragraphs: String = (0..rng.gen_range(3..8))
+            .map(|_| {
+                let count = rng.gen_range(2..5);
+                let sentences: Vec<&str> = SENTENCES.choose_multiple(&mut rng, count).copied().collect();
+                format!("<p>{}</p>\n", sentences.join(" "))
+            })
+            .collect();
+        let date = format!(
+            "{} {}, 20{}",
+            rng.gen_range(1..29),
+            ["March", "June", "September", "November"].choose(&mut rng).unwrap(),
+            rng.gen_range(19..25)
+        );
+
+        let mut page = template
+            .replace("{{title}}", TITLES.choose(&mut rng).unwrap())
+            .replace("{{author}}", AUTHORS.choose(&mut rng).unwrap())
+            .replace("{{date}}", &date)
+            .replace("{{content}}", &paragraphs)
+            .replace(chosen.placeholder(), &chosen.render(&BASE64.encode(payload)));
+
+        for slot in SLOTS {
+            page = page.replace(slot.placeholder(), "");
+        }
+        page
+    }
+
+    /// Finds a payload hidden by `render`. Templates may use the same markers for
+    /// their own markup, so every occurrence is tried until one decodes.
+    fn extract(page: &str) -> Result<Vec<u8>, String> {
+        for slot in SLOTS {
+            let (open, close) = slot.markers();
+            for (index, _) in page.match_indices(open) {
+                let start = index + open.len();
+                let Some(length) = page[start..].find(close) else {
+                    continue;
+                };
+                match BASE64.decode(&page[start..start + length]) {
+                    Ok(payload) if !payload.is_empty() => return Ok(payload),
+                    _ => continue,
+                }
+            }
+        }
+        Err("No payload found in page".to_string())
+    }
+}
+
+impl Carrier for HtmlCarrier {
+    fn encode_request(&self, payload: &[u8]) -> Result<CarrierRequest, String> {
+        let mut rng = rand::thread_rng();
+        let author = AUTHORS.choose(&mut rng).unwrap();
+        let body = format!(
+            "post_id={}&author={}&email={}%40example.com&{}={}",
+            rng.gen_range(100..1000),
+            author.replace(' ', "+"),
+            author.split(' ').next().unwrap_or_default().to_ascii_lowercase(),
+            FIELD,
+            BASE64_URL.encode(payload)
+        );
+
+        Ok(CarrierRequest {
+            method: "POST".to_string(),
+            path: PATH.to_string(),
+            query: None,
+            headers: vec![("content-type".to_string(), "application/x-www-form-urlencoded".to_string())],
+            body: body.into_bytes(),
+        })
+    }
+
+    fn decode_request(&self, request: &CarrierRequest) -> Result<Vec<u8>, String> {
+        expect_route(request, "POST", PATH)?;
+
+        let form = String::from_utf8_lossy(&request.body);
+        let value = form
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == FIELD)
+            .map(|(_, value)| value)
+            .ok_or_else(|| format!("Missing {} form field", FIELD))?;
+
+        BASE64_URL.decode(value).map_err(|e| e.to_string())
+    }
+
+    fn encode_response(&self, payload: &[u8]) -> Result<CarrierResponse, String> {
+        Ok(CarrierResponse {
+            status: 200,
+            headers: vec![("content-type".to_string(), "text/html; charset=utf-8".to_string())],
+            body: self.render(payload).into_bytes(),
+        })
+    }
+
+    fn decode_response(&self, response: &CarrierResponse) -> Result<Vec<u8>, String> {
+        Self::extract(&String::from_utf8_lossy(&response.body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The built-in template with only one slot left to hide the payload in
+    fn carrier_using(slot: Slot) -> HtmlCarrier {
+        let template = SLOTS
+            .iter()
+            .filter(|other| other.placeholder() != slot.placeholder())
+            .fold(DEFAULT_TEMPLATE.to_string(), |template, other| template.replace(other.placeholder(), ""));
+        HtmlCarrier { templates: vec![template] }
+    }
+
+    #[test]
+    fn responses_round_trip_through_every_slot() {
+        let payload: Vec<u8> = (0..=255).collect();
+        for slot in SLOTS {
+            let carrier = carrier_using(slot);
+            let response = carrier.encode_response(&payload).unwrap();
+            assert_eq!(carrier.decode_response(&response).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let carrier = HtmlCarrier::builtin();
+        let payload = b"a request payload".to_vec();
+        let request = carrier.encode_request(&payload).unwrap();
+        assert_eq!(carrier.decode_request(&request).unwrap(), payload);
+    }
+
+    #[test]
+    fn markers_in_the_template_are_skipped() {
+        let template = DEFAULT_TEMPLATE
+            .replace("{{head}}", "<script>window.__INITIAL_STATE__ = \"{not: base64}\";</script>")
+            .replace("<main>", "<main data-props=\"\">");
+        let carrier = HtmlCarrier { templates: vec![template] };
+        let payload = b"the real payload".to_vec();
+        let response = carrier.encode_response(&payload).unwrap();
+        assert_eq!(carrier.decode_response(&response).unwrap(), payload);
+    }
+
+    #[test]
+    fn pages_without_a_payload_are_refused() {
+        assert!(HtmlCarrier::extract(DEFAULT_TEMPLATE).is_err());
+    }
+}
@@ -0,0 +1,343 @@
+use std::fs;
+use std::path::Path;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use super::{expect_route, Carrier, CarrierRequest, CarrierResponse};
+
+const PATH: &str = "/upload";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const LENGTH_PREFIX: usize = 4; // Payload length is stored big-endian in the first 32 hidden bits
+const GENERATED_COVERS: usize = 4; // Covers generated when no cover directory is configured
+const GENERATED_WIDTH: usize = 640;
+const GENERATED_HEIGHT: usize = 480;
+const DEFAULT_DECODE_LIMIT: usize = 64 * 1024 * 1024; // Bytes an image may decode to unless larger payloads are expected
+
+/// An 8-bit RGB or RGBA image that payloads can be hidden in
+#[derive(Clone)]
+struct Cover {
+    width: usize,
+    height: usize,
+    channels: usize,
+    pixels: Vec<u8>,
+}
+
+impl Cover {
+    /// Number of payload bytes this cover can hold, one bit per colour channel
+    fn capacity(&self) -> usize {
+        (self.width * self.height * 3 / 8).saturating_sub(LENGTH_PREFIX)
+    }
+
+    /// Indices of the colour channels whose low bit carries data (alpha is left alone)
+    fn slots(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.width * self.height).flat_map(move |p| (0..3).map(move |c| p * self.channels + c))
+    }
+
+    /// Builds a soft gradient with a little noise, similar to a blurred photo
+    fn generate(width: usize, height: usize) -> Cover {
+        let mut rng = rand::thread_rng();
+        let base: [f32; 3] = [rng.gen_range(0.0..255.0), rng.gen_range(0.0..255.0), rng.gen_range(0.0..255.0)];
+        let tint: [f32; 3] = [rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)];
+
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                let t = (x + y) as f32 / (width + height) as f32;
+                for c in 0..3 {
+                    let value = base[c] + tint[c] * 96.0 * t + rng.gen_range(-6.0..6.0);
+                    pixels.push(value.clamp(0.0, 255.0) as u8);
+                }
+            }
+        }
+
+        Cover { width, height, channels: 3, pixels }
+    }
+
+    /// Upscales the cover with nearest-neighbour sampling to about the size that holds `needed` bytes
+    fn grow(&self, needed: usize) -> Cover {
+        let holds = |width: usize, height: usize| (width * height * 3 / 8).saturating_sub(LENGTH_PREFIX) >= needed;
+        let scale = ((needed + LENGTH_PREFIX) as f64 * 8.0 / 3.0 / (self.width * self.height) as f64).sqrt();
+        let (mut width, mut height) = ((self.width as f64 * scale).ceil() as usize, (self.height as f64 * scale).ceil() as usize);
+        while !holds(width, height) {
+            width += 1;
+            height += 1;
+        }
+
+        let mut pixels = Vec::with_capacity(width * height * self.channels);
+        for y in 0..height {
+            for x in 0..width {
+                let src = ((y * self.height / height) * self.width + x * self.width / width) * self.channels;
+                pixels.extend_from_slice(&self.pixels[src..src + self.channels]);
+            }
+        }
+
+        Cover { width, height, channels: self.channels, pixels }
+    }
+
+    /// Reads a PNG file and normalises it to 8-bit RGB or RGBA, refusing images
+    /// that would decode to more than `limit` bytes
+    fn decode(data: &[u8], limit: usize) -> Result<Cover, String> {
+        let mut decoder = png::Decoder::new_with_limits(data, png::Limits { bytes: limit });
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+        if reader.output_buffer_size() > limit {
+            return Err(format!("Image decodes to {} bytes, over the limit of {}", reader.output_buffer_size(), limit));
+        }
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+        buffer.truncate(info.buffer_size());
+
+        let (width, height) = (info.width as usize, info.height as usize);
+        let (channels, pixels) = match info.color_type {
+            png::ColorType::Rgb => (3, buffer),
+            png::ColorType::Rgba => (4, buffer),
+            png::ColorType::Grayscale => (3, buffer.iter().flat_map(|&v| [v, v, v]).collect()),
+            png::ColorType::GrayscaleAlpha => (
+                4,
+                buffer.chunks(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+            ),
+            other => return Err(format!("Unsupported PNG colour type: {:?}", other)),
+        };
+
+        Ok(Cover { width, height, channels, pixels })
+    }
+
+    /// Writes the cover out as a PNG file
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        let mut output = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut output, self.width as u32, self.height as u32);
+            encoder.set_color(if self.channels == 4 { png::ColorType::Rgba } else { png::ColorType::Rgb });
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+            writer.write_image_data(&self.pixels).map_err(|e| e.to_string())?;
+        }
+        Ok(output)
+    }
+}
+
+/// Hides payloads in the least significant bits of PNG images. Requests look like
+/// image uploads and responses look like the server sending an image back.
+pub struct PngCarrier {
+    covers: Vec<Cover>,
+    decode_limit: usize, // Most bytes an image received from the peer may decode to
+}
+
+impl PngCarrier {
+    /// Uses every PNG in `dir` as a cover image
+    pub fn from_dir(dir: &Path) -> Result<Self, String> {
+        let entries = fs::read_dir(dir).map_err(|e| format!("Failed to read cover directory {}: {}", dir.display(), e))?;
+
+        let mut covers = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png")) {
+                continue;
+            }
+            let data = fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            let cover = Cover::decode(&data, DEFAULT_DECODE_LIMIT).map_err(|e| format!("Invalid cover image {}: {}", path.display(), e))?;
+            covers.push(cover);
+        }
+
+        if covers.is_empty() {
+            return Err(format!("No PNG cover images found in {}", dir.display()));
+        }
+        Ok(PngCarrier { covers, decode_limit: DEFAULT_DECODE_LIMIT })
+    }
+
+    /// Uses a handful of generated gradient images as covers
+    pub fn generated() -> Self {
+        let covers = (0..GENERATED_COVERS)
+            .map(|_| Cover::generate(GENERATED_WIDTH, GENERATED_HEIGHT))
+            .collect();
+        PngCarrier { covers, decode_limit: DEFAULT_DECODE_LIMIT }
+    }
+
+    /// Lets received images grow large enough to hold payloads of up to `max_payload`
+    /// bytes. Twice what that takes in RGBA is allowed, for covers that fit it loosely.
+    pub fn with_max_payload(mut self, max_payload: usize) -> Self {
+        let pixels = max_payload.saturating_add(LENGTH_PREFIX).saturating_mul(8) / 3 + 1;
+        self.decode_limit = pixels.saturating_mul(4 * 2).max(DEFAULT_DECODE_LIMIT);
+        self
+    }
+
+    /// Picks a random cover that fits the payload, growing the largest one if none do
+    fn pick_cover(&self, needed: usize) -> Cover {
+        let fitting: Vec<&Cover> = self.covers.iter().filter(|c| c.capacity() >= needed).collect();
+        if let Some(cover) = fitting.choose(&mut rand::thread_rng()) {
+            return (*cover).clone();
+        }
+
+        let largest = self
+            .covers
+            .iter()
+            .max_by_key(|c| c.capacity())
+            .expect("PngCarrier always has at least one cover");
+        largest.grow(needed)
+    }
+
+    /// Embeds the payload into a cover and returns the resulting PNG file
+    fn embed(&self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        let mut cover = self.pick_cover(payload.len());
+
+        let length = u32::try_from(payload.len()).map_err(|_| "Payload too large for PNG carrier".to_string())?;
+        let data: Vec<u8> = length.to_be_bytes().iter().chain(payload).copied().collect();
+        let bits = data.iter().flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1));
+
+        let slots: Vec<usize> = cover.slots().collect();
+        for (slot, bit) in slots.into_iter().zip(bits) {
+            cover.pixels[slot] = (cover.pixels[slot] & !1) | bit;
+        }
+
+        cover.encode()
+    }
+
+    /// Recovers a payload hidden by `embed`
+    fn extract(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let cover = Cover::decode(data, self.decode_limit)?;
+        let bits: Vec<u8> = cover.slots().map(|slot| cover.pixels[slot] & 1).collect();
+        let bytes: Vec<u8> = bits
+            .chunks_exact(8)
+            .map(|bits| bits.iter().fold(0, |acc, bit| (acc << 1) | bit))
+            .collect();
+
+        if bytes.len() < LENGTH_PREFIX {
+            return Err("Image too small to hold a payload".to_string());
+        }
+        let length = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        if length > cover.capacity() {
+            return Err(format!("Declared payload length {} exceeds image capacity {}", length, cover.capacity()));
+        }
+
+        Ok(bytes[LENGTH_PREFIX..LENGTH_PREFIX + length].to_vec())
+    }
+}
+
+impl Carrier for PngCarrier {
+    fn encode_request(&self, payload: &[u8]) -> Result<CarrierRequest, String> {
+        let image = self.embed(payload)?;
+        let boundary = format!("----WebKitFormBoundary{:016x}", rand::thread_rng().gen::<u64>());
+        let filename = format!("IMG_{:04}.png", rand::thread_rng().gen_range(1000..10000));
+
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: image/png\r\n\r\n",
+            boundary, filename
+        )
+        .into_bytes();
+        body.extend_from_slice(&image);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+        Ok(CarrierRequest {
+            method: "POST".to_string(),
+            path: PATH.to_string(),
+            query: None,
+            headers: vec![(
+                "content-type".to_string(),
+                format!("multipart/form-data; boundary={}", boundary),
+            )],
+            body,
+        })
+    }
+
+    fn decode_request(&self, request: &CarrierRequest) -> Result<Vec<u8>, String> {
+        expect_route(request, "POST", PATH)?;
+
+        // The PNG decoder stops at the IEND chunk, so the closing boundary can be left in place
+        let start = request
+            .body
+            .windows(PNG_SIGNATURE.len())
+            .position(|window| window == PNG_SIGNATURE)
+            .ok_or_else(|| "No PNG image in upload".to_string())?;
+        self.extract(&request.body[start..])
+    }
+
+    fn encode_response(&self, payload: &[u8]) -> Result<CarrierResponse, String> {
+        Ok(CarrierResponse {
+            status: 200,
+            headers: vec![
+                ("content-type".to_string(), "image/png".to_string()),
+                ("cache-control".to_string(), "public, max-age=86400".to_string()),
+            ],
+            body: self.embed(payload)?,
+        })
+    }
+
+    fn decode_response(&self, response: &CarrierResponse) -> Result<Vec<u8>, String> {
+        self.extract(&response.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_carrier() -> PngCarrier {
+        PngCarrier {
+            covers: vec![Cover::generate(16, 16)],
+            decode_limit: DEFAULT_DECODE_LIMIT,
+        }
+    }
+
+    #[test]
+    fn requests_and_responses_round_trip() {
+        let carrier = small_carrier();
+        let payload: Vec<u8> = (0..=255).collect();
+        let request = carrier.encode_request(&payload).unwrap();
+        assert_eq!(carrier.decode_request(&request).unwrap(), payload);
+        let response = carrier.encode_response(&payload).unwrap();
+        assert_eq!(carrier.decode_response(&response).unwrap(), payload);
+    }
+
+    #[test]
+    fn covers_grow_to_fit_large_payloads() {
+        let carrier = small_carrier();
+        let payload = vec![0xa5; carrier.covers[0].capacity() * 5];
+        let response = carrier.encode_response(&payload).unwrap();
+        assert_eq!(carrier.decode_response(&response).unwrap(), payload);
+    }
+
+    #[test]
+    fn payloads_past_the_default_decode_limit_round_trip() {
+        // Hidden at a bit per channel, 9MB takes an image of 72MB
+        let payload: Vec<u8> = (0..9 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let carrier = small_carrier().with_max_payload(payload.len());
+        let request = carrier.encode_request(&payload).unwrap();
+        assert_eq!(carrier.decode_request(&request).unwrap(), payload);
+        // Without the larger limit the image is refused rather than decoded
+        assert!(small_carrier().decode_request(&request).is_err());
+    }
+
+    #[test]
+    fn alpha_is_left_alone() {
+        let pixels: Vec<u8> = (0..8 * 8).flat_map(|i| [i as u8, 100, 200, 77]).collect();
+        let carrier = PngCarrier {
+            covers: vec![Cover { width: 8, height: 8, channels: 4, pixels }],
+            decode_limit: DEFAULT_DECODE_LIMIT,
+        };
+        let image = carrier.embed(b"hidden").unwrap();
+        let cover = Cover::decode(&image, DEFAULT_DECODE_LIMIT).unwrap();
+        assert_eq!(cover.channels, 4);
+        assert!(cover.pixels.chunks(4).all(|pixel| pixel[3] == 77));
+        assert_eq!(carrier.extract(&image).unwrap(), b"hidden");
+    }
+
+    #[test]
+    fn impossible_lengths_are_refused() {
+        let mut cover = Cover::generate(8, 8);
+        // Every hidden bit set declares a far longer payload than the image holds
+        let slots: Vec<usize> = cover.slots().collect();
+        for slot in slots {
+            cover.pixels[slot] |= 1;
+        }
+        assert!(small_carrier().extract(&cover.encode().unwrap()).is_err());
+    }
+
+    #[test]
+    fn uploads_without_an_image_are_refused() {
+        let carrier = small_carrier();
+        let mut request = carrier.encode_request(b"payload").unwrap();
+        request.body = b"--boundary\r\n\r\nnot an image\r\n--boundary--\r\n".to_vec();
+        assert!(carrier.decode_request(&request).is_err());
+    }
+}
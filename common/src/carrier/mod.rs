@@ -0,0 +1,178 @@
+//! Carriers hide proxy payloads inside HTTP exchanges between the client and
+//! the masquerade server. The client uses `encode_request`/`decode_response`,
+//! the server uses the mirror image `decode_request`/`encode_response`.
+//! Payloads are already sealed by `crate::crypto` when they reach a carrier.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+mod body;
+mod header;
+mod html;
+mod image;
+mod query;
+
+pub use body::BodyCarrier;
+pub use header::HeaderCarrier;
+pub use html::HtmlCarrier;
+pub use image::PngCarrier;
+pub use query::QueryCarrier;
+
+/// An HTTP request as it travels from the client to the masquerade server
+#[derive(Debug, Clone, Default)]
+pub struct CarrierRequest {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// An HTTP response as it travels from the masquerade server to the client
+#[derive(Debug, Clone, Default)]
+pub struct CarrierResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl CarrierRequest {
+    /// Returns the first header matching `name` (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+impl CarrierResponse {
+    /// Returns the first header matching `name` (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Encodes opaque payloads into HTTP exchanges and back again
+pub trait Carrier: Send + Sync {
+    /// Hide a client payload inside an outgoing request
+    fn encode_request(&self, payload: &[u8]) -> Result<CarrierRequest, String>;
+
+    /// Recover the client payload from an incoming request
+    fn decode_request(&self, request: &CarrierRequest) -> Result<Vec<u8>, String>;
+
+    /// Hide a server payload inside an outgoing response
+    fn encode_response(&self, payload: &[u8]) -> Result<CarrierResponse, String>;
+
+    /// Recover the server payload from an incoming response
+    fn decode_response(&self, response: &CarrierResponse) -> Result<Vec<u8>, String>;
+}
+
+/// The carriers that can be selected from the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CarrierKind {
+    Query,
+    #[default]
+    Body,
+    Header,
+    Png,
+    Html,
+}
+
+/// Settings used by carriers that need more than their kind to be built
+#[derive(Debug, Clone, Default)]
+pub struct CarrierOptions {
+    pub cover_dir: Option<PathBuf>,    // Directory of PNG cover images for the png carrier
+    pub template_dir: Option<PathBuf>, // Directory of HTML page templates for the html carrier
+    pub max_payload: Option<usize>,    // Largest payload that must be recovered, if more than a carrier allows by default
+}
+
+impl CarrierKind {
+    /// Build the carrier implementation for this kind
+    pub fn build(self, options: &CarrierOptions) -> Result<Box<dyn Carrier>, String> {
+        Ok(match self {
+            CarrierKind::Query => Box::new(QueryCarrier),
+            CarrierKind::Body => Box::new(BodyCarrier),
+            CarrierKind::Header => Box::new(HeaderCarrier),
+            CarrierKind::Png => {
+                let carrier = match &options.cover_dir {
+                    Some(dir) => PngCarrier::from_dir(dir)?,
+                    None => PngCarrier::generated(),
+                };
+                match options.max_payload {
+                    Some(max_payload) => Box::new(carrier.with_max_payload(max_payload)),
+                    None => Box::new(carrier),
+                }
+            }
+            CarrierKind::Html => match &options.template_dir {
+                Some(dir) => Box::new(HtmlCarrier::from_dir(dir)?),
+                None => Box::new(HtmlCarrier::builtin()),
+            },
+        })
+    }
+}
+
+impl FromStr for CarrierKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "query" => Ok(CarrierKind::Query),
+            "body" => Ok(CarrierKind::Body),
+            "header" => Ok(CarrierKind::Header),
+            "png" => Ok(CarrierKind::Png),
+            "html" => Ok(CarrierKind::Html),
+            _ => Err(format!("Unknown carrier: {} (expected query, body, header, png or html)", s)),
+        }
+    }
+}
+
+impl fmt::Display for CarrierKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CarrierKind::Query => "query",
+            CarrierKind::Body => "body",
+            CarrierKind::Header => "header",
+            CarrierKind::Png => "png",
+            CarrierKind::Html => "html",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Checks that an incoming request was sent to the method and path a carrier expects
+fn expect_route(request: &CarrierRequest, method: &str, path: &str) -> Result<(), String> {
+    if !request.method.eq_ignore_ascii_case(method) || request.path != path {
+        return Err(format!("Unexpected route: {} {}", request.method, request.path));
+    }
+    Ok(())
+}
+
+/// Wraps a payload in a small JSON document, the way a typical API would reply
+fn json_envelope(payload: &[u8]) -> CarrierResponse {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+
+    let body = serde_json::json!({ "data": BASE64.encode(payload) }).to_string();
+    CarrierResponse {
+        status: 200,
+        headers: vec![("content-type".to_string(), "application/json".to_string())],
+        body: body.into_bytes(),
+    }
+}
+
+/// Unwraps a payload produced by `json_envelope`
+fn open_json_envelope(response: &CarrierResponse) -> Result<Vec<u8>, String> {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+
+    let value: serde_json::Value = serde_json::from_slice(&response.body).map_err(|e| e.to_string())?;
+    let data = value
+        .get("data")
+        .and_then(|d| d.as_str())
+        .ok_or_else(|| "Missing data field in response".to_string())?;
+    BASE64.decode(data).map_err(|e| e.to_string())
+}
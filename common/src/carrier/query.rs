@@ -0,0 +1,44 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine as _};
+
+use super::{expect_route, json_envelope, open_json_envelope, Carrier, CarrierRequest, CarrierResponse};
+
+const PATH: &str = "/search";
+const PARAM: &str = "q";
+
+/// Carries the payload in the query string of a search-style GET request.
+/// Simple, but subject to URL length limits and visible in access logs.
+pub struct QueryCarrier;
+
+impl Carrier for QueryCarrier {
+    fn encode_request(&self, payload: &[u8]) -> Result<CarrierRequest, String> {
+        Ok(CarrierRequest {
+            method: "GET".to_string(),
+            path: PATH.to_string(),
+            query: Some(format!("{}={}", PARAM, BASE64_URL.encode(payload))),
+            headers: Vec::new(),
+            body: Vec::new(),
+        })
+    }
+
+    fn decode_request(&self, request: &CarrierRequest) -> Result<Vec<u8>, String> {
+        expect_route(request, "GET", PATH)?;
+
+        let query = request.query.as_deref().unwrap_or_default();
+        let value = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == PARAM)
+            .map(|(_, value)| value)
+            .ok_or_else(|| format!("Missing {} query parameter", PARAM))?;
+
+        BASE64_URL.decode(value).map_err(|e| e.to_string())
+    }
+
+    fn encode_response(&self, payload: &[u8]) -> Result<CarrierResponse, String> {
+        Ok(json_envelope(payload))
+    }
+
+    fn decode_response(&self, response: &CarrierResponse) -> Result<Vec<u8>, String> {
+        open_json_envelope(response)
+    }
+}
@@ -0,0 +1,317 @@
+//! Layered options for both binaries. Every command line option can also be set
+//! in a TOML file named with `--config`, keyed by the option's long name, and in
+//! an environment variable named after it (`--max-retries` is `max-retries` in
+//! the file and `MASQUERADE_SERVER_MAX_RETRIES` for the server). The command line
+//! wins over the environment, which wins over the file. See docs/configuration.md.
+
+use std::error::Error as _;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, Command, CommandFactory, FromArgMatches};
+
+const CONFIG: &str = "config"; // Id of the option naming the file, which every binary has
+
+/// Parses a binary's options from its command line, environment and config
+/// file, exiting with a message when they are invalid just as clap would.
+/// Environment variables are named `<prefix>_<LONG_NAME>`.
+pub fn parse<T: CommandFactory + FromArgMatches>(prefix: &str) -> T {
+    try_parse_from(prefix, std::env::args_os().collect(), |name| std::env::var_os(name)).unwrap_or_else(|e| e.exit())
+}
+
+/// Parses options as `parse` does, from the given command line, looking up
+/// environment variables with `env`
+fn try_parse_from<T: CommandFactory + FromArgMatches>(
+    prefix: &str,
+    mut args: Vec<OsString>,
+    env: impl Fn(&str) -> Option<OsString>,
+) -> Result<T, clap::Error> {
+    // Variables are looked up here rather than by clap, which only reads the
+    // process environment, so help names each one the way clap would
+    let mut command = T::command().mut_args(|arg| match arg.get_long() {
+        Some(long) => {
+            let listed = format!("[env: {}]", env_name(prefix, long));
+            let help = match arg.get_help() {
+                Some(help) => format!("{} {}", help, listed),
+                None => listed,
+            };
+            arg.help(help)
+        }
+        None => arg,
+    });
+
+    // A first pass that ignores everything else tells which options the command
+    // line set, the file among them
+    let given = command
+        .clone()
+        .ignore_errors(true)
+        .disable_help_flag(true)
+        .disable_version_flag(true)
+        .try_get_matches_from(&args)
+        .ok();
+    let on_command_line = |arg: &Arg| {
+        let source = given.as_ref().and_then(|matches| matches.value_source(arg.get_id().as_str()));
+        matches!(source, Some(ValueSource::CommandLine))
+    };
+
+    // The environment's values, and then the file's, are spelled out as if given
+    // on the command line, each for the options nothing above it set. That keeps
+    // the layers in order while holding them to the same `requires` and
+    // `conflicts_with` rules, which clap would not check for defaults.
+    let mut from_env = Vec::new();
+    for arg in command.get_arguments().filter(|arg| !on_command_line(arg)) {
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        let name = env_name(prefix, long);
+        if let Some(value) = env(&name) {
+            from_env.push((arg.clone(), name, value));
+        }
+    }
+    let mut path = given.as_ref().and_then(|matches| matches.get_one::<PathBuf>(CONFIG).cloned());
+    for (arg, name, value) in &from_env {
+        if arg.get_id() == CONFIG {
+            path = Some(PathBuf::from(value));
+        }
+        args.extend(env_args(arg, name, value).map_err(|e| command.error(ErrorKind::InvalidValue, e))?);
+    }
+
+    if let Some(path) = &path {
+        let from_file = load(path, &command).map_err(|e| command.error(ErrorKind::InvalidValue, e))?;
+        for (arg, values) in from_file {
+            let from_env = from_env.iter().any(|(set, _, _)| set.get_id() == arg.get_id());
+            if !on_command_line(&arg) && !from_env {
+                args.extend(file_args(&arg, &values));
+            }
+        }
+    }
+
+    let matches = match (command.try_get_matches_from_mut(args), &path) {
+        (Ok(matches), _) => matches,
+        // Name the file, since the options at fault may be in it
+        (Err(e), Some(path)) if matches!(e.kind(), ErrorKind::ArgumentConflict | ErrorKind::MissingRequiredArgument) => {
+            let rendered = e.to_string();
+            let reason: Vec<&str> = rendered.lines().take_while(|line| !line.is_empty()).map(str::trim).collect();
+            let reason = reason.join(" ");
+            let message = format!("Invalid config file {}: {}", path.display(), reason.trim_start_matches("error: "));
+            return Err(command.error(e.kind(), message));
+        }
+        (Err(e), _) => return Err(e),
+    };
+    T::from_arg_matches(&matches).map_err(|e| e.format(&mut command))
+}
+
+/// The environment variable that sets an option
+fn env_name(prefix: &str, long: &str) -> String {
+    format!("{}_{}", prefix, long.to_ascii_uppercase().replace('-', "_"))
+}
+
+/// The command line arguments that give an option the value an environment variable set
+fn env_args(arg: &Arg, name: &str, value: &OsStr) -> Result<Vec<OsString>, String> {
+    let long = arg.get_long().unwrap_or_default();
+    if matches!(arg.get_action(), ArgAction::SetTrue) {
+        return match value.to_str() {
+            Some("true") => Ok(vec![format!("--{}", long).into()]),
+            Some("false") => Ok(Vec::new()),
+            _ => Err(format!("Invalid environment variable {}: must be true or false", name)),
+        };
+    }
+    let mut flag = OsString::from(format!("--{}=", long));
+    flag.push(value);
+    Ok(vec![flag])
+}
+
+/// The command line arguments that give an option the values a file set for it
+fn file_args(arg: &Arg, values: &[String]) -> Vec<OsString> {
+    let long = arg.get_long().unwrap_or_default();
+    if arg.get_action().takes_values() {
+        values.iter().map(|value| format!("--{}={}", long, value).into()).collect()
+    } else if values.iter().any(|value| value == "true") {
+        vec![format!("--{}", long).into()]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Reads the option values a config file sets, checking each against the option it is for
+fn load(path: &Path, command: &Command) -> Result<Vec<(Arg, Vec<String>)>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let table: toml::Table = text.parse().map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+
+    table
+        .into_iter()
+        .map(|(key, value)| {
+            let invalid = |problem: String| format!("Invalid config file {}: `{}` {}", path.display(), key, problem);
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(key.as_str()) && arg.get_id() != CONFIG)
+                .ok_or_else(|| invalid("is not a known option".to_string()))?;
+            let values = option_values(arg, value).map_err(invalid)?;
+            Ok((arg.clone(), values))
+        })
+        .collect()
+}
+
+/// Turns a TOML value into the strings an option would be given on the command line
+fn option_values(arg: &Arg, value: toml::Value) -> Result<Vec<String>, String> {
+    // Switches take no value on the command line, so the file says whether they are on
+    if matches!(arg.get_action(), ArgAction::SetTrue) {
+        return match value {
+            toml::Value::Boolean(on) => Ok(vec![on.to_string()]),
+            _ => Err("must be true or false".to_string()),
+        };
+    }
+
+    let repeatable = matches!(arg.get_action(), ArgAction::Append);
+    let items = match value {
+        toml::Value::Array(items) if repeatable => items,
+        toml::Value::Array(_) => return Err("takes a single value, not a list".to_string()),
+        value => vec![value],
+    };
+    let values = items
+        .into_iter()
+        .map(|item| match item {
+            toml::Value::String(s) => Ok(s),
+            toml::Value::Integer(n) => Ok(n.to_string()),
+            toml::Value::Float(n) => Ok(n.to_string()),
+            toml::Value::Boolean(b) => Ok(b.to_string()),
+            _ => Err(if repeatable { "must be a list of strings or numbers" } else { "must be a string or a number" }),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Each value goes through the option's own parser, so the file is held to the same rules as the command line
+    let probe = Arg::new("value").long("value").action(ArgAction::Append).value_parser(arg.get_value_parser().clone());
+    let args = values.iter().map(|value| format!("--value={}", value));
+    match Command::new("probe").arg(probe).try_get_matches_from(std::iter::once("probe".to_string()).chain(args)) {
+        Ok(_) => Ok(values),
+        Err(e) => Err(match e.source() {
+            Some(reason) => format!("is invalid: {}", reason),
+            None => format!("is invalid: {}", e.kind()),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[derive(Parser, Debug)]
+    struct Options {
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[arg(long, default_value_t = 3030)]
+        port: u16,
+        #[arg(long, default_value = "body")]
+        carrier: String,
+        #[arg(long)]
+        quiet: bool,
+        #[arg(long, value_delimiter = ',')]
+        allow: Vec<String>,
+        #[arg(long)]
+        user: Option<String>,
+        #[arg(long, requires = "user")]
+        pass: Option<String>,
+        #[arg(long, conflicts_with = "quiet")]
+        verbose: bool,
+    }
+
+    /// Writes a config file in a directory removed once the test drops it
+    fn file(contents: &str) -> (TempDir, String) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, contents).unwrap();
+        (dir, path.display().to_string())
+    }
+
+    /// Parses a command line, with `env` standing in for the environment
+    fn parse_args(args: &[&str], env: &[(&str, &str)]) -> Result<Options, clap::Error> {
+        let args = std::iter::once("test").chain(args.iter().copied()).map(OsString::from).collect();
+        let lookup = |name: &str| env.iter().find(|(key, _)| *key == name).map(|(_, value)| OsString::from(value));
+        try_parse_from("MASQUERADE_TEST", args, lookup)
+    }
+
+    #[test]
+    fn file_values_fill_in_options() {
+        let (_dir, path) = file("port = 8443\nquiet = true\nallow = [\"a\", 2]\nuser = \"sam\"\npass = \"x\"");
+        let options = parse_args(&["--config", &path], &[]).unwrap();
+        assert_eq!(options.port, 8443);
+        assert!(options.quiet);
+        assert_eq!(options.allow, ["a", "2"]);
+        assert_eq!(options.pass.as_deref(), Some("x"));
+        assert_eq!(options.carrier, "body");
+    }
+
+    #[test]
+    fn command_line_beats_environment_beats_file() {
+        let (_dir, path) = file("port = 1\ncarrier = \"png\"\nallow = [\"file\"]");
+        let env = [("MASQUERADE_TEST_CARRIER", "html"), ("MASQUERADE_TEST_PORT", "2")];
+        let options = parse_args(&["--config", &path, "--port", "3"], &env).unwrap();
+        assert_eq!((options.port, options.carrier.as_str()), (3, "html"));
+        assert_eq!(options.allow, ["file"]);
+    }
+
+    #[test]
+    fn the_file_can_be_named_in_the_environment() {
+        let (_dir, path) = file("port = 4");
+        assert_eq!(parse_args(&[], &[("MASQUERADE_TEST_CONFIG", &path)]).unwrap().port, 4);
+    }
+
+    #[test]
+    fn environment_values_are_split_and_checked() {
+        let env = [("MASQUERADE_TEST_ALLOW", "a,b"), ("MASQUERADE_TEST_QUIET", "true")];
+        let options = parse_args(&[], &env).unwrap();
+        assert_eq!(options.allow, ["a", "b"]);
+        assert!(options.quiet);
+        assert!(!parse_args(&[], &[("MASQUERADE_TEST_QUIET", "false")]).unwrap().quiet);
+
+        let e = parse_args(&[], &[("MASQUERADE_TEST_QUIET", "yes")]).unwrap_err();
+        assert!(e.to_string().contains("MASQUERADE_TEST_QUIET"), "{}", e);
+        assert!(parse_args(&[], &[("MASQUERADE_TEST_PORT", "eighty")]).is_err());
+    }
+
+    #[test]
+    fn switches_left_off_in_the_file_stay_off() {
+        let (_dir, path) = file("quiet = false");
+        assert!(!parse_args(&["--config", &path], &[]).unwrap().quiet);
+    }
+
+    #[test]
+    fn mistakes_name_the_key() {
+        let cases = [
+            ("colour = \"red\"", "`colour` is not a known option"),
+            ("port = \"eighty\"", "`port` is invalid"),
+            ("quiet = \"yes\"", "`quiet` must be true or false"),
+            ("port = [1, 2]", "`port` takes a single value"),
+            ("allow = [{ a = 1 }]", "`allow` must be a list"),
+            ("port = ", "Invalid config file"),
+        ];
+        for (contents, expected) in cases {
+            let (_dir, path) = file(contents);
+            let e = parse_args(&["--config", &path], &[]).unwrap_err();
+            assert!(e.to_string().contains(expected), "{}: {}", contents, e);
+        }
+    }
+
+    #[test]
+    fn file_values_are_held_to_requires_and_conflicts() {
+        let (_dir, path) = file("pass = \"x\"");
+        let e = parse_args(&["--config", &path], &[]).unwrap_err();
+        assert!(e.to_string().contains("Invalid config file"), "{}", e);
+        assert!(parse_args(&["--config", &path, "--user", "sam"], &[]).is_ok());
+
+        let (_dir, path) = file("quiet = true");
+        let e = parse_args(&["--config", &path, "--verbose"], &[]).unwrap_err();
+        assert!(e.to_string().contains("Invalid config file"), "{}", e);
+    }
+
+    #[test]
+    fn missing_files_are_an_error() {
+        let e = parse_args(&["--config", "/nonexistent/masquerade.toml"], &[]).unwrap_err();
+        assert!(e.to_string().contains("Failed to read"), "{}", e);
+    }
+}
@@ -0,0 +1,227 @@
+//! Authenticated encryption of proxy payloads. Every payload is sealed with
+//! XChaCha20-Poly1305 before a carrier ever sees it, so carriers only handle
+//! opaque ciphertext. Handshakes use a key derived from the pre-shared key,
+//! everything else uses a per-session key (see `crate::session`). The
+//! pre-shared key is stretched with Argon2id once at startup, so every guess at
+//! it costs an attacker holding a captured handshake as much as it cost us.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::Sha256;
+
+const KDF_SALT: &[u8] = b"masquerade-proxy";
+const PAYLOAD_KEY_INFO: &[u8] = b"payload key v1";
+const SESSION_KEY_INFO: &[u8] = b"session key v1";
+const NONCE_LEN: usize = 24;
+const PSK_SALT: &[u8] = b"masquerade-proxy pre-shared key v1";
+const PSK_MEMORY: u32 = 64 * 1024; // KiB of memory each stretch takes
+const PSK_PASSES: u32 = 3; // Passes over that memory
+
+/// A pre-shared key stretched into key material
+#[derive(Clone)]
+pub struct Psk([u8; 32]);
+
+impl Psk {
+    /// Stretches the pre-shared key given to both client and server. This is slow
+    /// on purpose, so it is done once and the result shared.
+    pub fn stretch(psk: &str) -> Result<Self, String> {
+        if psk.is_empty() {
+            return Err("Pre-shared key must not be empty".to_string());
+        }
+
+        let params = Params::new(PSK_MEMORY, PSK_PASSES, 1, Some(32)).map_err(|e| e.to_string())?;
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(psk.as_bytes(), PSK_SALT, &mut key)
+            .map_err(|e| e.to_string())?;
+        Ok(Psk(key))
+    }
+}
+
+/// Which way a payload is travelling. Bound into every ciphertext so a sealed
+/// request can never be replayed back to the client as a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl Direction {
+    /// The associated data for a payload: its direction and its place in the session
+    fn aad(self, sequence: u64) -> Vec<u8> {
+        let label: &[u8] = match self {
+            Direction::ClientToServer => b"client->server",
+            Direction::ServerToClient => b"server->client",
+        };
+        [label, &sequence.to_be_bytes()].concat()
+    }
+}
+
+/// Seals and opens payloads with a single symmetric key
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Derives the payload key from the pre-shared key
+    pub fn from_psk(psk: &Psk) -> Result<Self, String> {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(KDF_SALT), &psk.0)
+            .expand(PAYLOAD_KEY_INFO, &mut key)
+            .map_err(|e| e.to_string())?;
+
+        Ok(Cipher::from_key(key))
+    }
+
+    /// Derives a session key from a Diffie-Hellman shared secret. The pre-shared key
+    /// is mixed in and the handshake transcript is bound into the expansion.
+    pub fn from_exchange(psk: &Psk, shared_secret: &[u8], transcript: &[u8]) -> Result<Self, String> {
+        let mut input = shared_secret.to_vec();
+        input.extend_from_slice(&psk.0);
+
+        let mut info = SESSION_KEY_INFO.to_vec();
+        info.extend_from_slice(transcript);
+
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(KDF_SALT), &input)
+            .expand(&info, &mut key)
+            .map_err(|e| e.to_string())?;
+
+        Ok(Cipher::from_key(key))
+    }
+
+    fn from_key(key: [u8; 32]) -> Self {
+        Cipher {
+            aead: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    /// Encrypts a payload under a fresh random nonce, returning `nonce || ciphertext`.
+    /// `sequence` numbers the message within its session (handshakes use 0) and is
+    /// bound into the ciphertext, so it only opens as the message it was sealed as.
+    pub fn seal(&self, direction: Direction, sequence: u64, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead
+            .encrypt(&nonce, Payload { msg: plaintext, aad: &direction.aad(sequence) })
+            .map_err(|_| "Failed to encrypt payload".to_string())?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Verifies and decrypts a payload produced by `seal`
+    pub fn open(&self, direction: Direction, sequence: u64, sealed: &[u8]) -> Result<Vec<u8>, String> {
+        if sealed.len() < NONCE_LEN {
+            return Err("Sealed payload is too short".to_string());
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: &direction.aad(sequence) })
+            .map_err(|_| "Payload failed authentication".to_string())
+    }
+
+    /// Serializes a message to JSON and seals it
+    pub fn seal_json<T: Serialize>(&self, direction: Direction, sequence: u64, message: &T) -> Result<Vec<u8>, String> {
+        let plaintext = serde_json::to_vec(message).map_err(|e| e.to_string())?;
+        self.seal(direction, sequence, &plaintext)
+    }
+
+    /// Opens a sealed payload and parses the JSON message inside
+    pub fn open_json<T: DeserializeOwned>(&self, direction: Direction, sequence: u64, sealed: &[u8]) -> Result<T, String> {
+        let plaintext = self.open(direction, sequence, sealed)?;
+        serde_json::from_slice(&plaintext).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use super::*;
+
+    // Stretched once and shared by every test, since stretching is slow
+    fn secret() -> &'static Psk {
+        static PSK: OnceLock<Psk> = OnceLock::new();
+        PSK.get_or_init(|| Psk::stretch("secret").unwrap())
+    }
+
+    fn other() -> &'static Psk {
+        static PSK: OnceLock<Psk> = OnceLock::new();
+        PSK.get_or_init(|| Psk::stretch("other").unwrap())
+    }
+
+    #[test]
+    fn sealed_payloads_open() {
+        let cipher = Cipher::from_psk(secret()).unwrap();
+        let sealed = cipher.seal(Direction::ClientToServer, 7, b"payload").unwrap();
+        assert_eq!(cipher.open(Direction::ClientToServer, 7, &sealed).unwrap(), b"payload");
+
+        let message = vec!["a".to_string(), "b".to_string()];
+        let sealed = cipher.seal_json(Direction::ServerToClient, 0, &message).unwrap();
+        assert_eq!(cipher.open_json::<Vec<String>>(Direction::ServerToClient, 0, &sealed).unwrap(), message);
+    }
+
+    #[test]
+    fn every_seal_uses_a_fresh_nonce() {
+        let cipher = Cipher::from_psk(secret()).unwrap();
+        let first = cipher.seal(Direction::ClientToServer, 1, b"payload").unwrap();
+        let second = cipher.seal(Direction::ClientToServer, 1, b"payload").unwrap();
+        assert_ne!(first[..NONCE_LEN], second[..NONCE_LEN]);
+    }
+
+    #[test]
+    fn tampered_payloads_are_refused() {
+        let cipher = Cipher::from_psk(secret()).unwrap();
+        let sealed = cipher.seal(Direction::ClientToServer, 1, b"payload").unwrap();
+        for i in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(cipher.open(Direction::ClientToServer, 1, &tampered).is_err(), "byte {}", i);
+        }
+        assert!(cipher.open(Direction::ClientToServer, 1, &sealed[..sealed.len() - 1]).is_err());
+        assert!(cipher.open(Direction::ClientToServer, 1, &sealed[..NONCE_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn payloads_only_open_as_what_they_were_sealed_as() {
+        let cipher = Cipher::from_psk(secret()).unwrap();
+        let sealed = cipher.seal(Direction::ClientToServer, 1, b"payload").unwrap();
+        assert!(cipher.open(Direction::ServerToClient, 1, &sealed).is_err());
+        assert!(cipher.open(Direction::ClientToServer, 2, &sealed).is_err());
+        assert!(Cipher::from_psk(other()).unwrap().open(Direction::ClientToServer, 1, &sealed).is_err());
+    }
+
+    #[test]
+    fn session_keys_depend_on_every_input() {
+        let cipher = Cipher::from_exchange(secret(), b"shared", b"transcript").unwrap();
+        let sealed = cipher.seal(Direction::ClientToServer, 1, b"payload").unwrap();
+        let same = Cipher::from_exchange(secret(), b"shared", b"transcript").unwrap();
+        assert!(same.open(Direction::ClientToServer, 1, &sealed).is_ok());
+        for other in [
+            Cipher::from_exchange(other(), b"shared", b"transcript").unwrap(),
+            Cipher::from_exchange(secret(), b"other", b"transcript").unwrap(),
+            Cipher::from_exchange(secret(), b"shared", b"other").unwrap(),
+        ] {
+            assert!(other.open(Direction::ClientToServer, 1, &sealed).is_err());
+        }
+    }
+
+    #[test]
+    fn empty_psks_are_refused() {
+        assert!(Psk::stretch("").is_err());
+    }
+
+    #[test]
+    fn stretching_is_deterministic() {
+        assert_eq!(Psk::stretch("secret").unwrap().0, secret().0);
+        assert_ne!(secret().0, other().0);
+    }
+}
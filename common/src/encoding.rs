@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+
+use brotli_decompressor::Decompressor as BrotliDecoder;
+use flate2::read::{DeflateDecoder, GzDecoder};
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+const BROTLI_BUFFER: usize = 4096; // Working buffer of a brotli decoder
+
+/// The encoded body as it arrives. Reading past what has arrived so far would
+/// block, which the decoders wrapped around it wait out without losing their place.
+#[derive(Clone, Default)]
+struct Feed(Arc<Mutex<Input>>);
+
+#[derive(Default)]
+struct Input {
+    data: VecDeque<u8>,
+    ended: bool, // Nothing more will arrive
+}
+
+impl Read for Feed {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut input = self.0.lock().unwrap();
+        if input.data.is_empty() && !input.ended {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        input.data.read(buffer)
+    }
+}
+
+type Stage = Box<dyn Read + Send>;
+
+/// Wraps a reader of a coding's output in one that undoes it
+fn undo(coding: &str, encoded: Stage) -> Option<Stage> {
+    Some(match coding {
+        "gzip" | "x-gzip" => Box::new(GzDecoder::new(encoded)),
+        "deflate" => Box::new(DeflateDecoder::new(encoded)),
+        "br" => Box::new(BrotliDecoder::new(encoded, BROTLI_BUFFER)),
+        // Only fails when out of memory, leaving the body to pass through encoded
+        "zstd" => Box::new(ZstdDecoder::new(encoded).ok()?),
+        _ => return None,
+    })
+}
+
+/// Undoes a body's Content-Encoding as it streams past. Codings stacked in one
+/// header (`gzip, br`) were applied in the order listed, so they are undone in
+/// reverse. Output is only produced as it is asked for, so a small body that
+/// decodes to a huge one never has to be held all at once.
+pub struct Decoder {
+    input: Feed,
+    output: Stage, // The input with every coding undone
+    identity: bool, // No coding to undo
+}
+
+impl Decoder {
+    /// A decoder that leaves the body as it is
+    pub fn identity() -> Self {
+        Self::new("").expect("No codings can always be undone")
+    }
+
+    /// The decoder for a Content-Encoding, or None if it names a coding we cannot undo
+    pub fn new(encoding: &str) -> Option<Self> {
+        let codings: Vec<String> = encoding
+            .rsplit(',')
+            .map(|coding| coding.trim().to_ascii_lowercase())
+            .filter(|coding| !coding.is_empty() && coding != "identity")
+            .collect();
+
+        let input = Feed::default();
+        let mut output: Stage = Box::new(input.clone());
+        for coding in &codings {
+            output = undo(coding, output)?;
+        }
+        Some(Decoder {
+            input,
+            output,
+            identity: codings.is_empty(),
+        })
+    }
+
+    /// Whether the body comes out as it went in, length included
+    pub fn is_identity(&self) -> bool {
+        self.identity
+    }
+
+    /// Hands over the next bytes of the body
+    pub fn feed(&mut self, encoded: &[u8]) {
+        self.input.0.lock().unwrap().data.extend(encoded);
+    }
+
+    /// Marks the end of the body
+    pub fn end(&mut self) {
+        self.input.0.lock().unwrap().ended = true;
+    }
+
+    /// Decodes into `output` as much as it holds of what the body so far allows,
+    /// returning how many bytes were written. Zero means more input is needed, or
+    /// once `end` has been called, that the body is done.
+    pub fn decode(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        match self.output.read(output) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            result => result,
+        }
+    }
+
+    /// Decodes a whole body at once
+    pub fn decode_all(mut self, body: &[u8]) -> io::Result<Vec<u8>> {
+        self.feed(body);
+        self.end();
+        let mut decoded = Vec::new();
+        self.output.read_to_end(&mut decoded)?;
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::{DeflateEncoder, GzEncoder};
+    use flate2::Compression;
+
+    use super::*;
+
+    const BROTLI_HELLO: &[u8] = b"\x8f\x02\x80hello\n\x03"; // "hello\n" as brotli
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd(data: &[u8]) -> Vec<u8> {
+        zstd::encode_all(data, 0).unwrap()
+    }
+
+    /// Feeds a body a byte at a time, decoding into a small buffer after each
+    fn trickle(mut decoder: Decoder, body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        let mut output = [0; 7];
+        for byte in body {
+            decoder.feed(&[*byte]);
+            loop {
+                let n = decoder.decode(&mut output)?;
+                if n == 0 {
+                    break;
+                }
+                decoded.extend_from_slice(&output[..n]);
+            }
+        }
+        decoder.end();
+        loop {
+            let n = decoder.decode(&mut output)?;
+            if n == 0 {
+                return Ok(decoded);
+            }
+            decoded.extend_from_slice(&output[..n]);
+        }
+    }
+
+    #[test]
+    fn each_coding_is_undone() {
+        let text = b"hello\n".repeat(100);
+        let cases = [
+            ("gzip", gzip(&text), text.clone()),
+            ("x-gzip", gzip(&text), text.clone()),
+            ("deflate", deflate(&text), text.clone()),
+            ("zstd", zstd(&text), text.clone()),
+            ("br", BROTLI_HELLO.to_vec(), b"hello\n".to_vec()),
+            ("identity", text.clone(), text.clone()),
+            ("", text.clone(), text.clone()),
+        ];
+        for (encoding, body, expected) in cases {
+            let decoded = Decoder::new(encoding).unwrap().decode_all(&body).unwrap();
+            assert_eq!(decoded, expected, "{}", encoding);
+        }
+    }
+
+    #[test]
+    fn stacked_codings_are_undone_in_reverse() {
+        let text = b"hello\n";
+        assert_eq!(Decoder::new("br, gzip").unwrap().decode_all(&gzip(BROTLI_HELLO)).unwrap(), text);
+        assert_eq!(Decoder::new("gzip, zstd").unwrap().decode_all(&zstd(&gzip(text))).unwrap(), text);
+        assert_eq!(Decoder::new("Deflate , GZIP").unwrap().decode_all(&gzip(&deflate(text))).unwrap(), text);
+        // The wrong order does not decode
+        assert!(Decoder::new("zstd, gzip").unwrap().decode_all(&zstd(&gzip(text))).is_err());
+    }
+
+    #[test]
+    fn bodies_can_arrive_a_byte_at_a_time() {
+        let text: Vec<u8> = (0..20_000u32).flat_map(|n| n.to_le_bytes()).collect();
+        assert_eq!(trickle(Decoder::new("gzip").unwrap(), &gzip(&text)).unwrap(), text);
+        assert_eq!(trickle(Decoder::new("deflate").unwrap(), &deflate(&text)).unwrap(), text);
+        assert_eq!(trickle(Decoder::new("zstd").unwrap(), &zstd(&text)).unwrap(), text);
+        assert_eq!(trickle(Decoder::new("br").unwrap(), BROTLI_HELLO).unwrap(), b"hello\n");
+        assert_eq!(trickle(Decoder::new("gzip, zstd").unwrap(), &zstd(&gzip(&text))).unwrap(), text);
+        assert_eq!(trickle(Decoder::identity(), &text).unwrap(), text);
+    }
+
+    #[test]
+    fn output_is_only_produced_as_asked_for() {
+        // Doubly compressed zeros, a few hundred bytes that decode to megabytes
+        let bomb = gzip(&vec![0; 8 * 1024 * 1024]);
+        let mut decoder = Decoder::new("gzip, gzip").unwrap();
+        decoder.feed(&gzip(&bomb));
+        let mut output = [0; 100];
+        assert_eq!(decoder.decode(&mut output).unwrap(), 100);
+    }
+
+    #[test]
+    fn truncated_bodies_are_errors() {
+        let body = gzip(b"hello\n");
+        assert!(Decoder::new("gzip").unwrap().decode_all(&body[..body.len() - 4]).is_err());
+        let body = zstd(b"hello\n");
+        assert!(Decoder::new("zstd").unwrap().decode_all(&body[..body.len() - 2]).is_err());
+        assert!(Decoder::new("br").unwrap().decode_all(&BROTLI_HELLO[..5]).is_err());
+    }
+
+    #[test]
+    fn unknown_codings_are_refused() {
+        assert!(Decoder::new("compress").is_none());
+        assert!(Decoder::new("gzip, xyz").is_none());
+    }
+}
@@ -0,0 +1,37 @@
+//! Files the binaries write for themselves, such as generated private keys.
+
+use std::io::Write;
+use std::path::Path;
+
+/// Writes a file only the current user can read, refusing to replace one that exists
+pub fn write_private(path: &Path, contents: &str) -> Result<(), String> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    file.write_all(contents.as_bytes()).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_files_are_written_once() {
+        let path = std::env::temp_dir().join(format!("masquerade-private-{}.pem", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        write_private(&path, "secret").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "secret");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        assert!(write_private(&path, "other").is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "secret");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
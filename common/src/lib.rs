@@ -0,0 +1,11 @@
+//! Code shared by the masquerade client and server: the proxy wire format and
+//! the carriers that disguise it as ordinary HTTP traffic.
+
+pub mod carrier;
+pub mod config;
+pub mod crypto;
+pub mod encoding;
+pub mod files;
+pub mod mux;
+pub mod session;
+pub mod structs;
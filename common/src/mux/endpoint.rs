@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::time::{sleep, timeout, Duration};
+
+use super::{Core, Event, Frame, MAX_FRAME_DATA, WINDOW};
+
+const OPEN_TIMEOUT: u64 = 30; // Seconds to wait for the peer to answer an Open
+const SPACE_RECHECK: u64 = 100; // How often a blocked socket reader rechecks its send buffer (milliseconds)
+
+/// Datagrams for one association, as (peer address, payload) pairs
+pub type DatagramReceiver = mpsc::UnboundedReceiver<(String, Vec<u8>)>;
+type DatagramSender = mpsc::UnboundedSender<(String, Vec<u8>)>;
+
+/// Requests from the peer that the owner of an endpoint has to act on
+pub enum Incoming {
+    Open { stream: u32, target: String },                           // Connect the stream, then `accept` or `refuse` it
+    Datagram { association: u32, peer: String, data: Vec<u8> },     // For an association nobody has claimed
+    Dissociate { association: u32 },
+}
+
+/// The local end of a stream: where received bytes go, and how to stop the socket reader
+struct Pipe {
+    to_socket: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    from_peer: Option<mpsc::UnboundedReceiver<Vec<u8>>>, // Held until a socket is attached
+    opened: Option<oneshot::Sender<Result<(), String>>>,
+    abort: Arc<Notify>,
+}
+
+impl Pipe {
+    fn new() -> Self {
+        let (to_socket, from_peer) = mpsc::unbounded_channel();
+        Pipe {
+            to_socket: Some(to_socket),
+            from_peer: Some(from_peer),
+            opened: None,
+            abort: Arc::new(Notify::new()),
+        }
+    }
+
+    fn close(mut self, reason: &str) {
+        if let Some(opened) = self.opened.take() {
+            let _ = opened.send(Err(reason.to_string()));
+        }
+        self.abort.notify_one();
+    }
+}
+
+/// One side of a multiplexed connection, pumping bytes between local sockets and
+/// the protocol state. Whoever owns it moves frames to and from the peer.
+pub struct Endpoint {
+    core: Mutex<Core>,
+    pipes: Mutex<HashMap<u32, Pipe>>, // Always locked after `core`
+    associations: Mutex<HashMap<u32, DatagramSender>>,
+    ready: Notify,                    // There may be frames to send
+    space: Notify,                    // Acks may have freed room in send buffers
+}
+
+impl Endpoint {
+    pub fn new(accepts_opens: bool) -> Self {
+        Endpoint {
+            core: Mutex::new(Core::new(accepts_opens)),
+            pipes: Mutex::new(HashMap::new()),
+            associations: Mutex::new(HashMap::new()),
+            ready: Notify::new(),
+            space: Notify::new(),
+        }
+    }
+
+    /// Asks the peer to connect stream `stream` to `target` and waits for the answer
+    pub async fn open(&self, stream: u32, target: &str) -> Result<(), String> {
+        let (opened, answer) = oneshot::channel();
+        {
+            let mut core = self.core.lock().unwrap();
+            core.open(stream, target);
+            let mut pipe = Pipe::new();
+            pipe.opened = Some(opened);
+            self.pipes.lock().unwrap().insert(stream, pipe);
+        }
+        self.ready.notify_one();
+
+        match timeout(Duration::from_secs(OPEN_TIMEOUT), answer).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err("Stream closed before it opened".to_string()),
+            Err(_) => {
+                self.refuse(stream, "Timed out opening stream");
+                Err(format!("Timed out opening stream to {}", target))
+            }
+        }
+    }
+
+    /// Confirms a stream the peer asked for
+    pub fn accept(&self, stream: u32) {
+        self.core.lock().unwrap().accept(stream);
+        self.ready.notify_one();
+    }
+
+    /// Resets a stream, telling the peer why
+    pub fn refuse(&self, stream: u32, reason: &str) {
+        self.core.lock().unwrap().reset(stream, reason);
+        self.ready.notify_one();
+    }
+
+    /// Pumps bytes between an open stream and `socket` until both directions have
+    /// closed. Returns the bytes read from and written to the socket.
+    pub async fn attach(self: &Arc<Self>, stream: u32, socket: TcpStream) -> (u64, u64) {
+        let (from_peer, abort) = {
+            let mut pipes = self.pipes.lock().unwrap();
+            match pipes.get_mut(&stream) {
+                Some(pipe) => (pipe.from_peer.take(), pipe.abort.clone()),
+                None => (None, Arc::new(Notify::new())),
+            }
+        };
+        let Some(mut from_peer) = from_peer else {
+            return (0, 0);
+        };
+        let (mut reader, mut writer) = socket.into_split();
+
+        let endpoint = self.clone();
+        let abort_reader = abort.clone();
+        let upload = tokio::spawn(async move {
+            let mut buffer = vec![0; MAX_FRAME_DATA];
+            let mut total = 0;
+            loop {
+                let buffered = {
+                    let core = endpoint.core.lock().unwrap();
+                    if !core.contains(stream) {
+                        break;
+                    }
+                    core.buffered(stream)
+                };
+                if buffered >= WINDOW as usize {
+                    tokio::select! {
+                        _ = endpoint.space.notified() => {}
+                        _ = sleep(Duration::from_millis(SPACE_RECHECK)) => {}
+                        _ = abort_reader.notified() => break,
+                    }
+                    continue;
+                }
+
+                tokio::select! {
+                    read = reader.read(&mut buffer) => match read {
+                        Ok(0) | Err(_) => {
+                            endpoint.core.lock().unwrap().finish(stream);
+                            endpoint.ready.notify_one();
+                            break;
+                        }
+                        Ok(n) => {
+                            endpoint.core.lock().unwrap().write(stream, &buffer[..n]);
+                            endpoint.ready.notify_one();
+                            total += n as u64;
+                        }
+                    },
+                    _ = abort_reader.notified() => break,
+                }
+            }
+            total
+        });
+
+        let endpoint = self.clone();
+        let download = tokio::spawn(async move {
+            let mut total = 0;
+            while let Some(chunk) = from_peer.recv().await {
+                if writer.write_all(&chunk).await.is_err() {
+                    endpoint.refuse(stream, "Connection closed");
+                    abort.notify_one();
+                    break;
+                }
+                endpoint.core.lock().unwrap().consumed(stream, chunk.len());
+                endpoint.ready.notify_one();
+                total += chunk.len() as u64;
+            }
+            let _ = writer.shutdown().await;
+            total
+        });
+
+        (upload.await.unwrap_or_default(), download.await.unwrap_or_default())
+    }
+
+    /// Claims an association, returning where its datagrams from the peer arrive
+    pub fn associate(&self, association: u32) -> DatagramReceiver {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.associations.lock().unwrap().insert(association, sender);
+        self.ready.notify_one();
+        receiver
+    }
+
+    /// Releases an association; with `tell_peer` the peer is asked to release it too
+    pub fn dissociate(&self, association: u32, tell_peer: bool) {
+        self.associations.lock().unwrap().remove(&association);
+        if tell_peer {
+            self.core.lock().unwrap().dissociate(association);
+            self.ready.notify_one();
+        }
+    }
+
+    pub fn send_datagram(&self, association: u32, peer: &str, data: &[u8]) {
+        self.core.lock().unwrap().send_datagram(association, peer, data);
+        self.ready.notify_one();
+    }
+
+    /// Applies frames from the peer and returns what the caller has to act on
+    pub fn receive(&self, frames: Vec<Frame>) -> Vec<Incoming> {
+        let mut incoming = Vec::new();
+        {
+            let mut core = self.core.lock().unwrap();
+            let events = core.receive(frames);
+            let mut pipes = self.pipes.lock().unwrap();
+
+            for event in events {
+                match event {
+                    Event::Open { stream, target } => {
+                        pipes.insert(stream, Pipe::new());
+                        incoming.push(Incoming::Open { stream, target });
+                    }
+                    Event::Opened { stream } => {
+                        if let Some(opened) = pipes.get_mut(&stream).and_then(|p| p.opened.take()) {
+                            let _ = opened.send(Ok(()));
+                        }
+                    }
+                    Event::Data { stream, data } => {
+                        if let Some(to_socket) = pipes.get(&stream).and_then(|p| p.to_socket.as_ref()) {
+                            let _ = to_socket.send(data);
+                        }
+                    }
+                    Event::Finished { stream } => {
+                        if let Some(pipe) = pipes.get_mut(&stream) {
+                            pipe.to_socket = None;
+                        }
+                    }
+                    Event::Reset { stream, reason } => {
+                        if let Some(pipe) = pipes.remove(&stream) {
+                            pipe.close(&reason);
+                        }
+                    }
+                    Event::Datagram { association, peer, data } => {
+                        let associations = self.associations.lock().unwrap();
+                        match associations.get(&association) {
+                            Some(sender) => {
+                                let _ = sender.send((peer, data));
+                            }
+                            None => incoming.push(Incoming::Datagram { association, peer, data }),
+                        }
+                    }
+                    Event::Dissociate { association } => {
+                        self.associations.lock().unwrap().remove(&association);
+                        incoming.push(Incoming::Dissociate { association });
+                    }
+                }
+            }
+        }
+
+        self.space.notify_waiters();
+        self.ready.notify_one();
+        incoming
+    }
+
+    /// Collects frames for the next exchange with the peer
+    pub fn take_frames(&self) -> Vec<Frame> {
+        let mut core = self.core.lock().unwrap();
+        let frames = core.take_frames();
+
+        // Streams the core has finished with no longer need their pipes
+        let mut pipes = self.pipes.lock().unwrap();
+        let gone: Vec<u32> = pipes.keys().copied().filter(|&stream| !core.contains(stream)).collect();
+        for stream in gone {
+            if let Some(pipe) = pipes.remove(&stream) {
+                pipe.close("Stream reset");
+            }
+        }
+        frames
+    }
+
+    pub fn has_outbound(&self) -> bool {
+        self.core.lock().unwrap().has_outbound()
+    }
+
+    pub fn stream_count(&self) -> usize {
+        self.core.lock().unwrap().stream_count()
+    }
+
+    pub fn association_count(&self) -> usize {
+        self.associations.lock().unwrap().len()
+    }
+
+    /// Whether any stream or association is in use, so the peer may have something for us
+    pub fn is_active(&self) -> bool {
+        self.stream_count() > 0 || !self.associations.lock().unwrap().is_empty()
+    }
+
+    /// See `Core::resync`
+    pub fn resync(&self) {
+        self.core.lock().unwrap().resync();
+    }
+
+    /// Waits until there may be frames to send
+    pub async fn outbound_ready(&self) {
+        self.ready.notified().await;
+    }
+
+    /// Resets every stream and stops all socket pumps
+    pub fn shutdown(&self) {
+        let mut core = self.core.lock().unwrap();
+        *core = Core::new(false);
+        for (_, pipe) in self.pipes.lock().unwrap().drain() {
+            pipe.close("Connection closed");
+        }
+        self.associations.lock().unwrap().clear();
+    }
+}
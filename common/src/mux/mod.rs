@@ -0,0 +1,657 @@
+//! Multiplexing of many byte streams over a series of short request/response
+//! exchanges. Each stream is identified by a client-chosen id. Data frames carry
+//! byte offsets so lost exchanges can be retransmitted and reordered exchanges
+//! reassembled; acks are cumulative, window updates bound how far ahead the
+//! sender may run, and close frames occupy one virtual byte so they are acked
+//! like data.
+//!
+//! Datagrams for UDP associations ride along in the same batches. They are not
+//! retransmitted; like UDP itself, a lost exchange simply drops them.
+//!
+//! `Core` is the protocol state machine and does no IO. `Endpoint` wires it up
+//! to sockets with tokio tasks.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use serde::{Deserialize, Serialize};
+use tokio::time::{Duration, Instant};
+
+mod endpoint;
+
+pub use endpoint::{DatagramReceiver, Endpoint, Incoming};
+
+pub const WINDOW: u64 = 256 * 1024; // Bytes a receiver accepts beyond what it has written out
+pub const MAX_FRAME_DATA: usize = 32 * 1024; // Largest data frame
+pub const MAX_BATCH_DATA: usize = 256 * 1024; // Most data bytes sent in one exchange
+pub const MAX_DATAGRAM: usize = 16 * 1024; // Largest datagram payload relayed
+const MAX_QUEUED_DATAGRAMS: usize = 256; // Older datagrams are dropped once this many wait to be sent
+const RETRANSMIT_AFTER: Duration = Duration::from_secs(3); // Unacked frames are resent after this long
+
+/// A single unit of the multiplexing protocol
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Frame {
+    Open {
+        stream: u32,
+        target: String,     // host:port for the server to connect to
+    },
+    Opened {
+        stream: u32,
+    },
+    Data {
+        stream: u32,
+        offset: u64,        // Stream offset of the first byte
+        data: String,       // Base64 encoded bytes
+    },
+    Ack {
+        stream: u32,
+        offset: u64,        // Everything before this offset has been received
+    },
+    Window {
+        stream: u32,
+        limit: u64,         // The sender may send bytes up to (not including) this offset
+    },
+    Close {
+        stream: u32,
+        offset: u64,        // Final offset; the stream carries no data at or beyond it
+    },
+    Reset {
+        stream: u32,
+        reason: String,
+    },
+    Datagram {
+        association: u32,
+        peer: String,       // Destination host:port from the client, source address from the server
+        data: String,       // Base64 encoded payload
+    },
+    Dissociate {
+        association: u32,
+    },
+}
+
+impl Frame {
+    /// The stream a frame belongs to; datagram frames belong to none
+    pub fn stream(&self) -> Option<u32> {
+        let stream = match self {
+            Frame::Open { stream, .. }
+            | Frame::Opened { stream }
+            | Frame::Data { stream, .. }
+            | Frame::Ack { stream, .. }
+            | Frame::Window { stream, .. }
+            | Frame::Close { stream, .. }
+            | Frame::Reset { stream, .. } => *stream,
+            Frame::Datagram { .. } | Frame::Dissociate { .. } => return None,
+        };
+        Some(stream)
+    }
+
+    /// Whether this frame carries something new for the peer, as opposed to bookkeeping
+    pub fn is_payload(&self) -> bool {
+        !matches!(self, Frame::Opened { .. } | Frame::Ack { .. } | Frame::Window { .. })
+    }
+}
+
+/// Something the owner of a `Core` needs to act on
+#[derive(Debug)]
+pub enum Event {
+    Open { stream: u32, target: String },
+    Opened { stream: u32 },
+    Data { stream: u32, data: Vec<u8> },
+    Finished { stream: u32 },
+    Reset { stream: u32, reason: String },
+    Datagram { association: u32, peer: String, data: Vec<u8> },
+    Dissociate { association: u32 },
+}
+
+struct Sent {
+    data: Vec<u8>,
+    at: Instant,
+}
+
+#[derive(Default)]
+struct Stream {
+    // Opening
+    target: Option<String>,         // Open not yet answered (client side)
+    open_sent: Option<Instant>,
+    accepted: bool,                 // We connected the stream the peer asked for (server side)
+    opened_needed: bool,            // Opened must be sent (server side)
+
+    // Sending half
+    queued: Vec<u8>,                // Bytes not yet sent
+    next_offset: u64,               // Offset of the first queued byte
+    unacked: BTreeMap<u64, Sent>,   // Sent but not yet acknowledged, by offset
+    acked: u64,
+    limit: u64,
+    finished: bool,                 // No more bytes will be queued
+    close_sent: Option<Instant>,
+
+    // Receiving half
+    recv_next: u64,
+    reorder: BTreeMap<u64, Vec<u8>>,
+    recv_final: Option<u64>,
+    consumed: u64,
+    advertised: u64,
+    ack_needed: bool,
+    window_needed: bool,
+
+    reset: Option<String>,          // Reset to send to the peer
+}
+
+impl Stream {
+    fn new() -> Self {
+        Stream {
+            limit: WINDOW,
+            advertised: WINDOW,
+            ..Default::default()
+        }
+    }
+
+    fn due(sent: Option<Instant>, now: Instant) -> bool {
+        sent.is_none_or(|at| now.duration_since(at) >= RETRANSMIT_AFTER)
+    }
+
+    fn close_pending(&self) -> bool {
+        self.finished && self.queued.is_empty() && self.acked <= self.next_offset
+    }
+
+    fn wants_to_send(&self, now: Instant) -> bool {
+        self.reset.is_some()
+            || self.opened_needed
+            || self.ack_needed
+            || self.window_needed
+            || (self.target.is_some() && Self::due(self.open_sent, now))
+            || (!self.queued.is_empty() && self.next_offset < self.limit)
+            || self.unacked.values().any(|sent| Self::due(Some(sent.at), now))
+            || (self.close_pending() && Self::due(self.close_sent, now))
+    }
+
+    /// Both directions have closed and been acknowledged
+    fn done(&self) -> bool {
+        let sent_all = self.finished && self.queued.is_empty() && self.acked > self.next_offset;
+        let received_all = self.recv_final.is_some_and(|last| self.recv_next > last);
+        sent_all && received_all && !self.ack_needed
+    }
+}
+
+/// Protocol state for every stream on one side of a multiplexed connection
+pub struct Core {
+    accepts_opens: bool,            // Only the server side accepts Open frames
+    streams: HashMap<u32, Stream>,
+    highest_opened: Option<u32>,
+    orphans: Vec<Frame>,            // Replies for streams that are already gone
+    datagrams: VecDeque<Frame>,     // Datagrams waiting for the next exchange
+}
+
+impl Core {
+    pub fn new(accepts_opens: bool) -> Self {
+        Core {
+            accepts_opens,
+            streams: HashMap::new(),
+            highest_opened: None,
+            orphans: Vec::new(),
+            datagrams: VecDeque::new(),
+        }
+    }
+
+    pub fn stream_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Asks the peer to open a new stream to `target`
+    pub fn open(&mut self, stream: u32, target: &str) {
+        let mut state = Stream::new();
+        state.target = Some(target.to_string());
+        self.streams.insert(stream, state);
+    }
+
+    /// Confirms a stream the peer asked to open
+    pub fn accept(&mut self, stream: u32) {
+        if let Some(state) = self.streams.get_mut(&stream) {
+            state.accepted = true;
+            state.opened_needed = true;
+        }
+    }
+
+    /// Queues bytes to send on a stream
+    pub fn write(&mut self, stream: u32, data: &[u8]) {
+        if let Some(state) = self.streams.get_mut(&stream) {
+            state.queued.extend_from_slice(data);
+        }
+    }
+
+    /// Marks the sending half of a stream as finished
+    pub fn finish(&mut self, stream: u32) {
+        if let Some(state) = self.streams.get_mut(&stream) {
+            state.finished = true;
+        }
+    }
+
+    /// Aborts a stream in both directions
+    pub fn reset(&mut self, stream: u32, reason: &str) {
+        if let Some(state) = self.streams.get_mut(&stream) {
+            state.reset = Some(reason.to_string());
+        }
+    }
+
+    /// Records that `n` received bytes were written out, opening the window again
+    pub fn consumed(&mut self, stream: u32, n: usize) {
+        if let Some(state) = self.streams.get_mut(&stream) {
+            state.consumed += n as u64;
+            let limit = state.consumed + WINDOW;
+            if limit - state.advertised >= WINDOW / 4 {
+                state.advertised = limit;
+                state.window_needed = true;
+            }
+        }
+    }
+
+    /// Bytes queued or in flight on a stream, used to push back on whoever is writing
+    pub fn buffered(&self, stream: u32) -> usize {
+        self.streams
+            .get(&stream)
+            .map(|s| (s.next_offset + s.queued.len() as u64).saturating_sub(s.acked) as usize)
+            .unwrap_or(0)
+    }
+
+    pub fn contains(&self, stream: u32) -> bool {
+        self.streams.contains_key(&stream)
+    }
+
+    /// After an exchange may have been lost, re-announce acks and windows and resend everything unacked
+    pub fn resync(&mut self) {
+        for state in self.streams.values_mut() {
+            state.ack_needed = true;
+            state.window_needed = true;
+            state.open_sent = None;
+            state.close_sent = None;
+            for sent in state.unacked.values_mut() {
+                sent.at = Instant::now() - RETRANSMIT_AFTER;
+            }
+        }
+    }
+
+    /// Queues a datagram for an association, dropping the oldest if too many are waiting
+    pub fn send_datagram(&mut self, association: u32, peer: &str, data: &[u8]) {
+        if self.datagrams.len() >= MAX_QUEUED_DATAGRAMS {
+            self.datagrams.pop_front();
+        }
+        self.datagrams.push_back(Frame::Datagram {
+            association,
+            peer: peer.to_string(),
+            data: BASE64.encode(data),
+        });
+    }
+
+    /// Tells the peer an association is over
+    pub fn dissociate(&mut self, association: u32) {
+        self.datagrams.push_back(Frame::Dissociate { association });
+    }
+
+    pub fn has_outbound(&self) -> bool {
+        let now = Instant::now();
+        !self.orphans.is_empty() || !self.datagrams.is_empty() || self.streams.values().any(|s| s.wants_to_send(now))
+    }
+
+    /// Applies frames received from the peer
+    pub fn receive(&mut self, frames: Vec<Frame>) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        for frame in frames {
+            // A frame for a stream we never opened means we lost its state, e.g. on a restart
+            let Some(stream) = frame.stream() else {
+                match frame {
+                    Frame::Datagram { association, peer, data } => {
+                        if let Ok(data) = BASE64.decode(data) {
+                            events.push(Event::Datagram { association, peer, data });
+                        }
+                    }
+                    Frame::Dissociate { association } => events.push(Event::Dissociate { association }),
+                    _ => {}
+                }
+                continue;
+            };
+            let never_opened = self.highest_opened.is_none_or(|highest| stream > highest);
+            let opening = matches!(frame, Frame::Open { .. } | Frame::Reset { .. });
+            if self.accepts_opens && never_opened && !opening {
+                self.orphans.push(Frame::Reset { stream, reason: "Unknown stream".to_string() });
+                continue;
+            }
+
+            match frame {
+                Frame::Open { stream, target } => {
+                    if !self.accepts_opens {
+                        continue;
+                    }
+                    if let Some(state) = self.streams.get_mut(&stream) {
+                        // A retransmitted Open; repeat the answer if we already gave one
+                        state.opened_needed |= state.accepted;
+                    } else if self.highest_opened.is_none_or(|highest| stream > highest) {
+                        self.highest_opened = Some(stream);
+                        self.streams.insert(stream, Stream::new());
+                        events.push(Event::Open { stream, target });
+                    }
+                }
+                Frame::Opened { stream } => {
+                    if let Some(state) = self.streams.get_mut(&stream) {
+                        if state.target.take().is_some() {
+                            events.push(Event::Opened { stream });
+                        }
+                    }
+                }
+                Frame::Data { stream, offset, data } => {
+                    let Some(state) = self.streams.get_mut(&stream) else {
+                        continue;
+                    };
+                    let Ok(data) = BASE64.decode(data) else {
+                        continue;
+                    };
+                    state.ack_needed = true;
+
+                    let Some(end) = offset.checked_add(data.len() as u64) else {
+                        // No stream runs that long, so the peer has lost track of it
+                        let reason = "Data offset out of range".to_string();
+                        state.reset = Some(reason.clone());
+                        events.push(Event::Reset { stream, reason });
+                        continue;
+                    };
+                    if end <= state.recv_next || end > state.advertised {
+                        continue; // Duplicate, or more than the window allows
+                    }
+                    if offset > state.recv_next {
+                        state.reorder.insert(offset, data);
+                        continue;
+                    }
+
+                    let fresh = data[(state.recv_next - offset) as usize..].to_vec();
+                    state.recv_next = end;
+                    let mut delivered = fresh;
+
+                    // Pull in anything that arrived early and now lines up
+                    while let Some(entry) = state.reorder.first_entry() {
+                        let (start, chunk) = (*entry.key(), entry.get().len() as u64);
+                        if start > state.recv_next {
+                            break;
+                        }
+                        let chunk_data = entry.remove();
+                        if start + chunk > state.recv_next {
+                            delivered.extend_from_slice(&chunk_data[(state.recv_next - start) as usize..]);
+                            state.recv_next = start + chunk;
+                        }
+                    }
+
+                    events.push(Event::Data { stream, data: delivered });
+                    if state.recv_final == Some(state.recv_next) {
+                        state.recv_next += 1;
+                        events.push(Event::Finished { stream });
+                    }
+                }
+                Frame::Ack { stream, offset } => {
+                    if let Some(state) = self.streams.get_mut(&stream) {
+                        if offset > state.acked {
+                            state.acked = offset;
+                            state.unacked.retain(|start, sent| start + sent.data.len() as u64 > offset);
+                        }
+                    }
+                }
+                Frame::Window { stream, limit } => {
+                    if let Some(state) = self.streams.get_mut(&stream) {
+                        state.limit = state.limit.max(limit);
+                    }
+                }
+                Frame::Close { stream, offset } => {
+                    let Some(state) = self.streams.get_mut(&stream) else {
+                        // The stream is gone, so our final ack was lost; repeat it
+                        self.orphans.push(Frame::Ack { stream, offset: offset.saturating_add(1) });
+                        continue;
+                    };
+                    state.ack_needed = true;
+                    if state.recv_final.is_none() {
+                        state.recv_final = Some(offset);
+                        if state.recv_next == offset {
+                            state.recv_next += 1;
+                            events.push(Event::Finished { stream });
+                        }
+                    }
+                }
+                Frame::Reset { stream, reason } => {
+                    if self.streams.remove(&stream).is_some() {
+                        events.push(Event::Reset { stream, reason });
+                    }
+                }
+                Frame::Datagram { .. } | Frame::Dissociate { .. } => {} // Handled above
+            }
+        }
+
+        events
+    }
+
+    /// Collects the frames to send in the next exchange, and forgets finished streams
+    pub fn take_frames(&mut self) -> Vec<Frame> {
+        let now = Instant::now();
+        let mut frames = std::mem::take(&mut self.orphans);
+        let mut budget = MAX_BATCH_DATA;
+
+        while budget > 0 {
+            let Some(datagram) = self.datagrams.pop_front() else {
+                break;
+            };
+            if let Frame::Datagram { data, .. } = &datagram {
+                budget = budget.saturating_sub(data.len());
+            }
+            frames.push(datagram);
+        }
+
+        for (&stream, state) in self.streams.iter_mut() {
+            if let Some(reason) = state.reset.clone() {
+                frames.push(Frame::Reset { stream, reason });
+                continue;
+            }
+
+            if let Some(target) = &state.target {
+                if Stream::due(state.open_sent, now) {
+                    frames.push(Frame::Open { stream, target: target.clone() });
+                    state.open_sent = Some(now);
+                }
+            }
+            if std::mem::take(&mut state.opened_needed) {
+                frames.push(Frame::Opened { stream });
+            }
+            if std::mem::take(&mut state.ack_needed) {
+                frames.push(Frame::Ack { stream, offset: state.recv_next });
+            }
+            if std::mem::take(&mut state.window_needed) {
+                frames.push(Frame::Window { stream, limit: state.advertised });
+            }
+
+            // Resend anything that has gone unacknowledged for too long
+            for (&offset, sent) in state.unacked.iter_mut() {
+                if budget == 0 {
+                    break;
+                }
+                if Stream::due(Some(sent.at), now) {
+                    sent.at = now;
+                    budget = budget.saturating_sub(sent.data.len());
+                    frames.push(Frame::Data { stream, offset, data: BASE64.encode(&sent.data) });
+                }
+            }
+
+            // Send new data as far as the peer's window and this exchange's budget allow
+            while !state.queued.is_empty() && state.next_offset < state.limit && budget > 0 {
+                let room = (state.limit - state.next_offset) as usize;
+                let n = state.queued.len().min(MAX_FRAME_DATA).min(room).min(budget);
+                let chunk: Vec<u8> = state.queued.drain(..n).collect();
+
+                frames.push(Frame::Data { stream, offset: state.next_offset, data: BASE64.encode(&chunk) });
+                state.unacked.insert(state.next_offset, Sent { data: chunk, at: now });
+                state.next_offset += n as u64;
+                budget -= n;
+            }
+
+            if state.close_pending() && Stream::due(state.close_sent, now) {
+                frames.push(Frame::Close { stream, offset: state.next_offset });
+                state.close_sent = Some(now);
+            }
+        }
+
+        self.streams.retain(|_, state| state.reset.is_none() && !state.done());
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends everything `from` has queued to `to`
+    fn deliver(from: &mut Core, to: &mut Core) -> Vec<Event> {
+        to.receive(from.take_frames())
+    }
+
+    /// The bytes delivered to the owner in a batch of events
+    fn received(events: &[Event]) -> Vec<u8> {
+        events
+            .iter()
+            .flat_map(|event| match event {
+                Event::Data { data, .. } => data.clone(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    fn data(stream: u32, offset: u64, data: &[u8]) -> Frame {
+        Frame::Data { stream, offset, data: BASE64.encode(data) }
+    }
+
+    /// A client and server with stream 1 open between them
+    fn connected() -> (Core, Core) {
+        let (mut client, mut server) = (Core::new(false), Core::new(true));
+        client.open(1, "example.com:80");
+        let events = deliver(&mut client, &mut server);
+        assert!(matches!(&events[..], [Event::Open { stream: 1, target }] if target == "example.com:80"));
+        server.accept(1);
+        let events = deliver(&mut server, &mut client);
+        assert!(matches!(events[..], [Event::Opened { stream: 1 }]));
+        (client, server)
+    }
+
+    #[test]
+    fn data_flows_both_ways() {
+        let (mut client, mut server) = connected();
+        client.write(1, b"request");
+        assert_eq!(received(&deliver(&mut client, &mut server)), b"request");
+        server.write(1, b"response");
+        assert_eq!(received(&deliver(&mut server, &mut client)), b"response");
+    }
+
+    #[test]
+    fn early_data_waits_for_the_gap_to_fill() {
+        let (_, mut server) = connected();
+        assert!(server.receive(vec![data(1, 5, b" world")]).is_empty());
+        assert!(server.receive(vec![data(1, 8, b"rld!")]).is_empty());
+        assert_eq!(received(&server.receive(vec![data(1, 0, b"hello")])), b"hello world!");
+        // Retransmissions of what was already delivered are dropped
+        assert!(server.receive(vec![data(1, 0, b"hello")]).is_empty());
+        assert_eq!(received(&server.receive(vec![data(1, 10, b"d!?")])), b"?");
+    }
+
+    #[test]
+    fn acks_release_sent_data() {
+        let (mut client, mut server) = connected();
+        client.write(1, b"payload");
+        deliver(&mut client, &mut server);
+        assert_eq!(client.buffered(1), 7);
+
+        let frames = server.take_frames();
+        assert!(frames.iter().any(|frame| matches!(frame, Frame::Ack { stream: 1, offset: 7 })));
+        client.receive(frames);
+        assert_eq!(client.buffered(1), 0);
+    }
+
+    #[test]
+    fn unacked_data_is_resent_after_a_resync() {
+        let (mut client, mut server) = connected();
+        client.write(1, b"lost");
+        client.take_frames(); // The exchange carrying it never arrives
+        assert!(client.take_frames().iter().all(|frame| !matches!(frame, Frame::Data { .. })));
+        client.resync();
+        assert_eq!(received(&deliver(&mut client, &mut server)), b"lost");
+    }
+
+    #[test]
+    fn senders_stay_within_the_window() {
+        let (mut client, mut server) = connected();
+        client.write(1, &vec![7; 2 * WINDOW as usize]);
+        let mut delivered = 0;
+        for _ in 0..4 {
+            delivered += received(&deliver(&mut client, &mut server)).len();
+        }
+        assert_eq!(delivered as u64, WINDOW);
+
+        // Once the receiver writes its bytes out, the window opens again
+        server.consumed(1, delivered);
+        deliver(&mut server, &mut client);
+        assert!(!received(&deliver(&mut client, &mut server)).is_empty());
+    }
+
+    #[test]
+    fn data_beyond_the_window_is_dropped() {
+        let (_, mut server) = connected();
+        assert!(server.receive(vec![data(1, WINDOW, b"x")]).is_empty());
+    }
+
+    #[test]
+    fn data_running_past_the_largest_offset_resets_the_stream() {
+        let (_, mut server) = connected();
+        let events = server.receive(vec![data(1, u64::MAX - 1, b"overflow")]);
+        assert!(matches!(&events[..], [Event::Reset { stream: 1, .. }]));
+        assert!(matches!(&server.take_frames()[..], [Frame::Reset { stream: 1, .. }]));
+        assert_eq!(server.stream_count(), 0);
+    }
+
+    #[test]
+    fn closed_streams_are_forgotten_once_both_sides_finish() {
+        let (mut client, mut server) = connected();
+        client.write(1, b"bye");
+        client.finish(1);
+        let events = deliver(&mut client, &mut server);
+        assert_eq!(received(&events), b"bye");
+        assert!(events.iter().any(|event| matches!(event, Event::Finished { stream: 1 })));
+
+        server.finish(1);
+        let events = deliver(&mut server, &mut client);
+        assert!(events.iter().any(|event| matches!(event, Event::Finished { stream: 1 })));
+        for _ in 0..3 {
+            deliver(&mut client, &mut server);
+            deliver(&mut server, &mut client);
+        }
+        assert_eq!((client.stream_count(), server.stream_count()), (0, 0));
+    }
+
+    #[test]
+    fn resets_end_the_stream_on_both_sides() {
+        let (mut client, mut server) = connected();
+        client.reset(1, "gone");
+        let events = deliver(&mut client, &mut server);
+        assert!(matches!(&events[..], [Event::Reset { stream: 1, reason }] if reason == "gone"));
+        assert_eq!((client.stream_count(), server.stream_count()), (0, 0));
+    }
+
+    #[test]
+    fn frames_for_unknown_streams_are_answered_with_a_reset() {
+        let mut server = Core::new(true);
+        assert!(server.receive(vec![data(9, 0, b"stale")]).is_empty());
+        assert!(matches!(&server.take_frames()[..], [Frame::Reset { stream: 9, .. }]));
+    }
+
+    #[test]
+    fn datagrams_pass_through_and_old_ones_are_dropped() {
+        let (mut client, mut server) = (Core::new(false), Core::new(true));
+        for i in 0..=MAX_QUEUED_DATAGRAMS {
+            client.send_datagram(3, "example.com:53", &[i as u8]);
+        }
+        let events = deliver(&mut client, &mut server);
+        assert_eq!(events.len(), MAX_QUEUED_DATAGRAMS);
+        assert!(matches!(&events[0], Event::Datagram { association: 3, data, .. } if data == &[1]));
+    }
+}
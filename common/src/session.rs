@@ -0,0 +1,150 @@
+//! Forward-secret sessions between the client and the server.
+//!
+//! The first time the client talks to the server it sends a `ClientHello` with an
+//! ephemeral X25519 public key, sealed under the pre-shared key. The server answers
+//! with its own ephemeral key and an opaque session id. Both sides derive a session
+//! key from the shared secret and throw the ephemeral secrets away, so recorded
+//! traffic stays private even if the pre-shared key leaks later.
+//!
+//! Session traffic is framed as `session id || sequence || sealed message`;
+//! handshake traffic is just a message sealed under the pre-shared key. Neither has
+//! a visible type tag.
+//!
+//! Recorded traffic cannot be replayed. Each session message carries a sequence
+//! number that is bound into its ciphertext and that the server accepts only once,
+//! and replies are bound to the sequence of the message they answer. Hellos carry
+//! the time they were sent, so the server only has to remember recent ones.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::crypto::{Cipher, Psk};
+
+pub const SESSION_ID_LEN: usize = 16;
+const SEQUENCE_LEN: usize = 8;
+
+/// Opaque identifier the server uses to look up a session
+pub type SessionId = [u8; SESSION_ID_LEN];
+
+/// First handshake message, sent by the client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientHello {
+    pub public_key: String, // Base64 encoded X25519 public key
+    pub sent: u64,          // Unix time the hello was sent (seconds)
+}
+
+impl ClientHello {
+    /// How far apart the hello's timestamp and our clock are, in seconds
+    pub fn skew(&self) -> u64 {
+        unix_time().abs_diff(self.sent)
+    }
+}
+
+/// Handshake reply, sent by the server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerHello {
+    pub session_id: String, // Base64 encoded session id
+    pub public_key: String, // Base64 encoded X25519 public key
+    pub lifetime: u64,      // Seconds the server will keep the session alive
+}
+
+/// The client's half of a handshake in progress
+pub struct Handshake {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl Handshake {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Handshake { secret, public }
+    }
+
+    /// The message to send to the server
+    pub fn hello(&self) -> ClientHello {
+        ClientHello {
+            public_key: BASE64.encode(self.public.as_bytes()),
+            sent: unix_time(),
+        }
+    }
+
+    /// Completes the handshake with the server's reply, consuming the ephemeral secret
+    pub fn finish(self, psk: &Psk, reply: &ServerHello) -> Result<(SessionId, Cipher), String> {
+        let session_id = decode_session_id(&reply.session_id)?;
+        let server_public = decode_public_key(&reply.public_key)?;
+
+        let shared = self.secret.diffie_hellman(&server_public);
+        let transcript = transcript(&session_id, &self.public, &server_public);
+        let cipher = Cipher::from_exchange(psk, shared.as_bytes(), &transcript)?;
+        Ok((session_id, cipher))
+    }
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The server's side of the handshake: answers a hello and derives the session key
+pub fn accept(psk: &Psk, hello: &ClientHello, lifetime: u64) -> Result<(ServerHello, SessionId, Cipher), String> {
+    let client_public = decode_public_key(&hello.public_key)?;
+
+    let mut session_id = [0u8; SESSION_ID_LEN];
+    OsRng.fill_bytes(&mut session_id);
+
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(&client_public);
+    let transcript = transcript(&session_id, &client_public, &public);
+    let cipher = Cipher::from_exchange(psk, shared.as_bytes(), &transcript)?;
+
+    let reply = ServerHello {
+        session_id: BASE64.encode(session_id),
+        public_key: BASE64.encode(public.as_bytes()),
+        lifetime,
+    };
+    Ok((reply, session_id, cipher))
+}
+
+/// Prefixes a sealed message with the session it belongs to and its sequence number
+pub fn frame(session_id: &SessionId, sequence: u64, sealed: &[u8]) -> Vec<u8> {
+    [&session_id[..], &sequence.to_be_bytes(), sealed].concat()
+}
+
+/// Splits a framed payload into its session id, sequence number and sealed message
+pub fn unframe(payload: &[u8]) -> Option<(SessionId, u64, &[u8])> {
+    if payload.len() < SESSION_ID_LEN + SEQUENCE_LEN {
+        return None;
+    }
+    let (id, rest) = payload.split_at(SESSION_ID_LEN);
+    let (sequence, sealed) = rest.split_at(SEQUENCE_LEN);
+    Some((id.try_into().ok()?, u64::from_be_bytes(sequence.try_into().ok()?), sealed))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn transcript(session_id: &SessionId, client: &PublicKey, server: &PublicKey) -> Vec<u8> {
+    [&session_id[..], client.as_bytes(), server.as_bytes()].concat()
+}
+
+fn decode_session_id(encoded: &str) -> Result<SessionId, String> {
+    let bytes = BASE64.decode(encoded).map_err(|e| e.to_string())?;
+    bytes.try_into().map_err(|_| "Invalid session id length".to_string())
+}
+
+fn decode_public_key(encoded: &str) -> Result<PublicKey, String> {
+    let bytes: [u8; 32] = BASE64
+        .decode(encoded)
+        .map_err(|e| e.to_string())?
+        .try_into()
+        .map_err(|_| "Invalid public key length".to_string())?;
+    Ok(PublicKey::from(bytes))
+}
@@ -0,0 +1,109 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use serde::{Deserialize, Serialize};
+
+use crate::mux::Frame;
+
+/// What tunnel resets refused by the server's target policy start with, since
+/// resets carry only a reason
+pub const DENIED: &str = "Denied by server policy";
+
+/// Header fields in the order they were sent, as (name, Base64 encoded value)
+/// pairs. Repeated names stay separate and values need not be UTF-8. Response
+/// headers come from the server's HTTP client, which groups repeats of a name at
+/// the place the name first appeared, so a response only keeps the order of the
+/// names and of each name's values, not how different names were interleaved.
+pub type Headers = Vec<(String, String)>;
+
+pub fn encode_headers<'a>(headers: impl IntoIterator<Item = (&'a str, &'a [u8])>) -> Headers {
+    headers
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), BASE64.encode(value)))
+        .collect()
+}
+
+pub fn decode_headers(headers: &Headers) -> Result<Vec<(String, Vec<u8>)>, String> {
+    headers
+        .iter()
+        .map(|(name, value)| match BASE64.decode(value) {
+            Ok(value) => Ok((name.clone(), value)),
+            Err(e) => Err(format!("Undecodable value for header {}: {}", name, e)),
+        })
+        .collect()
+}
+
+/// Request sent from the client to the proxy server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyRequest {
+    pub target: String,                     // Target URL
+    pub method: String,                     // HTTP method (GET, POST, etc.)
+    pub headers: Headers,                   // Request headers
+    pub body: Option<String>,               // Optional Base64 encoded request body
+}
+
+/// Response sent from the proxy server back to the client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyResponse {
+    pub status: u16,                        // HTTP status code
+    #[serde(default)]
+    pub reason: Option<String>,             // Reason phrase the origin sent with the status
+    pub headers: Headers,                   // Response headers
+    pub body: String,                       // Base64 encoded response body, or its first piece
+    #[serde(default)]
+    pub stream: Option<u64>,                // If set, the rest of the body is fetched piece by piece with this id
+}
+
+/// Everything a client can send inside an established session
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Proxy(ProxyRequest),
+    Body {
+        stream: u64,        // The response body to fetch the next piece of
+    },
+    Mux {
+        connection: u64,    // Client-chosen id that outlives session rotation
+        frames: Vec<Frame>,
+        wait: bool,         // Nothing urgent was sent, so the server may hold the reply for new data
+        resync: bool,       // An earlier exchange failed; resend everything unacknowledged
+    },
+}
+
+/// Everything the server can answer with inside an established session
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Proxy(ProxyResponse),
+    Body {
+        data: String,       // Base64 encoded piece of a response body
+        last: bool,         // Nothing follows this piece
+    },
+    Mux {
+        frames: Vec<Frame>,
+    },
+    Error {
+        kind: ErrorKind,
+        message: String,
+    },
+}
+
+impl ServerMessage {
+    /// What kind of message this is, for logs that should not repeat its contents
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerMessage::Proxy(_) => "proxy response",
+            ServerMessage::Body { .. } => "body piece",
+            ServerMessage::Mux { .. } => "mux frames",
+            ServerMessage::Error { .. } => "error",
+        }
+    }
+}
+
+/// Why the server would not or could not carry out a request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Denied,           // The target is forbidden by the server's policy
+    RequestTooLarge,  // The request exceeds one of the server's size limits
+    ResponseTooLarge, // The origin's response exceeds one of the server's size limits
+    Failed,           // The response could not be read from the origin
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration, Instant};
+
+use common::encoding::Decoder;
+use common::structs::{ErrorKind, ServerMessage};
+
+use crate::limits::Limits;
+use crate::Refusal;
+
+const PIECE_SIZE: usize = 256 * 1024; // Most body bytes carried by one exchange
+const PIECES_AHEAD: usize = 4; // Pieces read from the origin before the client asks for them
+const PIECE_WAIT: u64 = 250; // How long buffered bytes wait for more before going out anyway (milliseconds)
+const FETCH_WAIT: u64 = 10; // How long a fetch waits for the origin before answering with an empty piece (seconds)
+const DECODE_STEP: usize = 16 * 1024; // Most decoded bytes produced at a time, each step charged before the next
+const BODY_IDLE_TIMEOUT: u64 = 60; // Bodies the client stops fetching are dropped (seconds)
+
+/// A stretch of a response body, in order
+struct Piece {
+    data: Vec<u8>,
+    last: bool, // Nothing follows this piece
+}
+
+type Pieces = mpsc::Receiver<Result<Piece, Refusal>>;
+
+struct Pending {
+    pieces: Arc<tokio::sync::Mutex<Pieces>>,
+    last_seen: Instant,
+}
+
+/// Response bodies too large for a single exchange, which the client fetches a
+/// piece at a time. Each is read from the origin only a few pieces ahead of the
+/// client, so a download of any size holds a bounded amount of memory.
+pub struct BodyTable {
+    bodies: Mutex<HashMap<u64, Pending>>,
+}
+
+impl BodyTable {
+    pub fn new() -> Self {
+        BodyTable {
+            bodies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts relaying a response body, returning its first piece and, if more
+    /// follows, the id the client fetches the rest with
+    pub async fn start(
+        &self,
+        response: reqwest::Response,
+        decoder: Decoder,
+        limits: &Limits,
+    ) -> Result<(Vec<u8>, Option<u64>), Refusal> {
+        // Only decoding can make a body grow, so plain bodies are held to the origin's limit alone
+        let budget = Budget {
+            read_limit: limits.response_body,
+            decoded_limit: (!decoder.is_identity()).then_some(limits.decoded_body),
+            read: 0,
+            decoded: 0,
+        };
+        let (sender, mut receiver) = mpsc::channel(PIECES_AHEAD);
+        tokio::spawn(read_body(response, decoder, budget, sender));
+
+        let first = fetch(&mut receiver).await?;
+        if first.last {
+            return Ok((first.data, None));
+        }
+
+        let id = rand::random();
+        let mut bodies = self.bodies.lock().unwrap();
+        bodies.retain(|_, pending| pending.last_seen.elapsed() < Duration::from_secs(BODY_IDLE_TIMEOUT));
+        bodies.insert(id, Pending {
+            pieces: Arc::new(tokio::sync::Mutex::new(receiver)),
+            last_seen: Instant::now(),
+        });
+        Ok((first.data, Some(id)))
+    }
+
+    /// Answers a client's request for the next piece of a body
+    pub async fn next(&self, id: u64) -> ServerMessage {
+        let pieces = {
+            let mut bodies = self.bodies.lock().unwrap();
+            bodies.retain(|_, pending| pending.last_seen.elapsed() < Duration::from_secs(BODY_IDLE_TIMEOUT));
+            match bodies.get_mut(&id) {
+                Some(pending) => {
+                    pending.last_seen = Instant::now();
+                    pending.pieces.clone()
+                }
+                None => return failed(Refusal::new(ErrorKind::Failed, "Unknown or expired response body")),
+            }
+        };
+
+        let piece = fetch(&mut *pieces.lock().await).await;
+        if !matches!(piece, Ok(Piece { last: false, .. })) {
+            self.bodies.lock().unwrap().remove(&id);
+        }
+        match piece {
+            Ok(piece) => ServerMessage::Body {
+                data: BASE64.encode(&piece.data),
+                last: piece.last,
+            },
+            Err(e) => failed(e),
+        }
+    }
+}
+
+/// Waits for the next piece, settling for an empty one if the origin is slow
+/// so the exchange carrying it does not hang
+async fn fetch(pieces: &mut Pieces) -> Result<Piece, Refusal> {
+    match timeout(Duration::from_secs(FETCH_WAIT), pieces.recv()).await {
+        Ok(Some(piece)) => piece,
+        Ok(None) => Err(Refusal::new(ErrorKind::Failed, "Response body ended unexpectedly")),
+        Err(_) => Ok(Piece { data: Vec::new(), last: false }),
+    }
+}
+
+fn failed(refusal: Refusal) -> ServerMessage {
+    println!("❌ {}", refusal.message);
+    refusal.into()
+}
+
+/// How many bytes a body may take up on the way in and once decoded, where
+/// limited, and how many it has taken up so far
+struct Budget {
+    read_limit: Option<u64>,
+    decoded_limit: Option<u64>,
+    read: u64,
+    decoded: u64,
+}
+
+impl Budget {
+    /// Counts bytes read from the origin and what they decoded to
+    fn spend(&mut self, read: usize, decoded: usize) -> Result<(), Refusal> {
+        self.read += read as u64;
+        self.decoded += decoded as u64;
+        if let Some(limit) = self.read_limit.filter(|&limit| self.read > limit) {
+            return Err(Refusal::new(
+                ErrorKind::ResponseTooLarge,
+                format!("Response body exceeds the limit of {} bytes", limit),
+            ));
+        }
+        if let Some(limit) = self.decoded_limit.filter(|&limit| self.decoded > limit) {
+            return Err(Refusal::new(
+                ErrorKind::ResponseTooLarge,
+                format!("Response body decodes to more than the limit of {} bytes", limit),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reads a body from the origin, decodes it and cuts it into pieces, stopping at
+/// the first byte over budget. Also stops as soon as nobody is waiting for the
+/// pieces any more, which closes the origin connection.
+async fn read_body(
+    mut response: reqwest::Response,
+    mut decoder: Decoder,
+    mut budget: Budget,
+    pieces: mpsc::Sender<Result<Piece, Refusal>>,
+) {
+    let mut buffer = Vec::new();
+    loop {
+        // Bytes that have waited long enough go out even if the piece is not full
+        let chunk = if buffer.is_empty() {
+            response.chunk().await
+        } else {
+            match timeout(Duration::from_millis(PIECE_WAIT), response.chunk()).await {
+                Ok(chunk) => chunk,
+                Err(_) => {
+                    let data = std::mem::take(&mut buffer);
+                    if pieces.send(Ok(Piece { data, last: false })).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            }
+        };
+
+        let chunk = match chunk {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                let refusal = Refusal::new(ErrorKind::Failed, format!("Failed to read response body: {}", e));
+                return abort(&pieces, refusal).await;
+            }
+        };
+
+        if let Err(refusal) = budget.spend(chunk.len(), 0) {
+            return abort(&pieces, refusal).await;
+        }
+        decoder.feed(&chunk);
+        if !drain(&mut decoder, &mut budget, &mut buffer, &pieces).await {
+            return;
+        }
+    }
+
+    decoder.end();
+    if drain(&mut decoder, &mut budget, &mut buffer, &pieces).await {
+        let _ = pieces.send(Ok(Piece { data: buffer, last: true })).await;
+    }
+}
+
+/// Decodes what the body so far allows into `buffer` a step at a time, charging
+/// each step to the budget and sending pieces as they fill. Returns false once
+/// the body has failed or nobody is waiting for it.
+async fn drain(
+    decoder: &mut Decoder,
+    budget: &mut Budget,
+    buffer: &mut Vec<u8>,
+    pieces: &mpsc::Sender<Result<Piece, Refusal>>,
+) -> bool {
+    let mut step = vec![0; DECODE_STEP];
+    loop {
+        let decoded = match decoder.decode(&mut step) {
+            Ok(0) => return true,
+            Ok(decoded) => decoded,
+            Err(e) => {
+                let refusal = Refusal::new(ErrorKind::Failed, format!("Failed to decode response body: {}", e));
+                abort(pieces, refusal).await;
+                return false;
+            }
+        };
+        if let Err(refusal) = budget.spend(0, decoded) {
+            abort(pieces, refusal).await;
+            return false;
+        }
+        buffer.extend_from_slice(&step[..decoded]);
+        if !send_full(buffer, pieces).await {
+            return false;
+        }
+    }
+}
+
+/// Tells whoever is waiting for the pieces why no more will come
+async fn abort(pieces: &mpsc::Sender<Result<Piece, Refusal>>, refusal: Refusal) {
+    let _ = pieces.send(Err(refusal)).await;
+}
+
+/// Sends the full pieces in `buffer`, keeping back what is left over. A full piece
+/// is only sent once more is known to follow it, so the last one is marked as such.
+/// Returns false once nobody is waiting for pieces.
+async fn send_full(buffer: &mut Vec<u8>, pieces: &mpsc::Sender<Result<Piece, Refusal>>) -> bool {
+    while buffer.len() > PIECE_SIZE {
+        let rest = buffer.split_off(PIECE_SIZE);
+        let data = std::mem::replace(buffer, rest);
+        if pieces.send(Ok(Piece { data, last: false })).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
@@ -0,0 +1,248 @@
+use std::path::{Component, Path, PathBuf};
+
+use percent_encoding::percent_decode_str;
+use tokio::time::Duration;
+use warp::http::{header, HeaderValue, Response, StatusCode};
+
+use common::carrier::CarrierRequest;
+
+const DECOY_TIMEOUT: u64 = 15; // Upstream timeout for the reverse-proxied decoy in seconds
+
+// Headers that only make sense for a single hop and must not be relayed
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+// Headers every reply carries, decoy page or carrier response, so that they cannot be told apart by them
+const SERVER_HEADERS: &[(header::HeaderName, &str)] = &[(header::SERVER, "nginx")];
+
+const WELCOME_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<title>Welcome to nginx!</title>
+<style>
+html { color-scheme: light dark; }
+body { width: 35em; margin: 0 auto;
+font-family: Tahoma, Verdana, Arial, sans-serif; }
+</style>
+</head>
+<body>
+<h1>Welcome to nginx!</h1>
+<p>If you see this page, the nginx web server is successfully installed and
+working. Further configuration is required.</p>
+
+<p>For online documentation and support please refer to
+<a href="http://nginx.org/">nginx.org</a>.<br/>
+Commercial support is available at
+<a href="http://nginx.com/">nginx.com</a>.</p>
+
+<p><em>Thank you for using nginx.</em></p>
+</body>
+</html>
+"#;
+
+/// What the server shows to anyone who is not an authenticated client, so that
+/// probing it looks no different from probing an ordinary website
+pub enum Decoy {
+    Builtin,         // A stock web server welcome page
+    Static(PathBuf), // Files served from a directory
+    Upstream {       // A real website, reverse proxied
+        base: String,
+        client: reqwest::Client,
+    },
+}
+
+impl Decoy {
+    pub fn from_dir(dir: PathBuf) -> Result<Self, String> {
+        if !dir.is_dir() {
+            return Err(format!("Decoy directory {} does not exist", dir.display()));
+        }
+        Ok(Decoy::Static(dir))
+    }
+
+    pub fn from_url(url: &str) -> Result<Self, String> {
+        let parsed = url::Url::parse(url).map_err(|e| format!("Invalid decoy URL {}: {}", url, e))?;
+        let client = reqwest::ClientBuilder::new()
+            .timeout(Duration::from_secs(DECOY_TIMEOUT))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        Ok(Decoy::Upstream {
+            base: parsed.as_str().trim_end_matches('/').to_string(),
+            client,
+        })
+    }
+
+    /// Answers a request exactly as the decoy site would
+    pub async fn respond(&self, request: &CarrierRequest) -> Response<Vec<u8>> {
+        match self {
+            Decoy::Builtin => builtin(request),
+            Decoy::Static(root) => serve_file(root, request).await,
+            Decoy::Upstream { base, client } => reverse_proxy(base, client, request).await,
+        }
+    }
+
+    /// Answers a request the server will not handle with `status`. A decoy site
+    /// of our own shows nginx's page for it, while a reverse-proxied one is
+    /// asked, so that the answer comes from the same server as every other.
+    pub async fn refuse(&self, status: StatusCode, request: &CarrierRequest) -> Response<Vec<u8>> {
+        match self {
+            Decoy::Upstream { .. } => self.respond(request).await,
+            _ => error_page(status),
+        }
+    }
+}
+
+fn builtin(request: &CarrierRequest) -> Response<Vec<u8>> {
+    if request.path != "/" && request.path != "/index.html" {
+        return error_page(StatusCode::NOT_FOUND);
+    }
+    if !is_read(&request.method) {
+        return error_page(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    page(StatusCode::OK, "text/html", WELCOME_PAGE.as_bytes().to_vec())
+}
+
+async fn serve_file(root: &Path, request: &CarrierRequest) -> Response<Vec<u8>> {
+    let Some(mut path) = resolve(root, &request.path) else {
+        return error_page(StatusCode::BAD_REQUEST);
+    };
+    if path.is_dir() {
+        path.push("index.html");
+    }
+
+    match tokio::fs::read(&path).await {
+        Ok(_) if !is_read(&request.method) => error_page(StatusCode::METHOD_NOT_ALLOWED),
+        Ok(contents) => page(StatusCode::OK, content_type(&path), contents),
+        Err(_) => match tokio::fs::read(root.join("404.html")).await {
+            Ok(contents) => page(StatusCode::NOT_FOUND, "text/html", contents),
+            Err(_) => error_page(StatusCode::NOT_FOUND),
+        },
+    }
+}
+
+async fn reverse_proxy(base: &str, client: &reqwest::Client, request: &CarrierRequest) -> Response<Vec<u8>> {
+    let mut url = format!("{}{}", base, request.path);
+    if let Some(query) = &request.query {
+        url.push('?');
+        url.push_str(query);
+    }
+
+    let Ok(method) = reqwest::Method::from_bytes(request.method.as_bytes()) else {
+        return error_page(StatusCode::BAD_REQUEST);
+    };
+
+    let mut upstream = client.request(method, &url).body(request.body.clone());
+    for (name, value) in &request.headers {
+        let name = name.to_ascii_lowercase();
+        if name != "host" && !HOP_BY_HOP.contains(&name.as_str()) {
+            upstream = upstream.header(name, value);
+        }
+    }
+
+    let response = match upstream.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            println!("❌ Decoy upstream failed: {}", e);
+            return error_page(StatusCode::BAD_GATEWAY);
+        }
+    };
+
+    let mut builder = Response::builder().status(response.status().as_u16());
+    for (name, value) in response.headers() {
+        if !HOP_BY_HOP.contains(&name.as_str()) {
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+    }
+
+    match response.bytes().await.map(|body| builder.body(body.to_vec())) {
+        Ok(Ok(mut response)) => {
+            server_headers(&mut response);
+            response
+        }
+        _ => error_page(StatusCode::BAD_GATEWAY),
+    }
+}
+
+/// Maps a request path onto a file below `root`, refusing anything that would escape it
+fn resolve(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(request_path).decode_utf8().ok()?;
+    let mut path = root.to_path_buf();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+fn is_read(method: &str) -> bool {
+    method.eq_ignore_ascii_case("GET") || method.eq_ignore_ascii_case("HEAD")
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" => "application/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "xml" => "text/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+fn page(status: StatusCode, content_type: &str, body: Vec<u8>) -> Response<Vec<u8>> {
+    let mut response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(body)
+        .expect("Static decoy headers are valid");
+    server_headers(&mut response);
+    response
+}
+
+/// Gives a reply the headers the server puts on every reply, replacing any it had
+pub fn server_headers(response: &mut Response<Vec<u8>>) {
+    for (name, value) in SERVER_HEADERS {
+        response.headers_mut().insert(name, HeaderValue::from_static(value));
+    }
+}
+
+/// The stock error page nginx serves for a status code
+pub fn error_page(status: StatusCode) -> Response<Vec<u8>> {
+    // nginx keeps the older names for a few codes
+    let reason = match status {
+        StatusCode::PAYLOAD_TOO_LARGE => "Request Entity Too Large",
+        StatusCode::URI_TOO_LONG => "Request-URI Too Large",
+        _ => status.canonical_reason().unwrap_or_default(),
+    };
+    let title = format!("{} {}", status.as_u16(), reason);
+    let body = format!(
+        "<html>\r\n<head><title>{0}</title></head>\r\n<body>\r\n<center><h1>{0}</h1></center>\r\n<hr><center>nginx</center>\r\n</body>\r\n</html>\r\n",
+        title
+    );
+    page(status, "text/html", body.into_bytes())
+}
@@ -0,0 +1,32 @@
+/// How large the parts of a proxied exchange may grow
+pub struct Limits {
+    pub request_body: usize,        // Bytes of request body
+    pub response_body: Option<u64>, // Bytes of response body as the origin sends it, if limited
+    pub decoded_body: u64,          // Bytes a compressed response body may decode to
+    pub headers: usize,             // Header fields in a request or response
+    pub header_bytes: usize,        // Bytes of header fields in a request or response
+}
+
+impl Limits {
+    /// Checks a block of header fields against the count and size limits
+    pub fn check_headers<'a>(&self, headers: impl IntoIterator<Item = (&'a str, &'a [u8])>) -> Result<(), String> {
+        let (count, bytes) = headers
+            .into_iter()
+            .fold((0, 0), |(count, bytes), (name, value)| (count + 1, bytes + name.len() + value.len() + 4));
+        if count > self.headers {
+            return Err(format!("{} header fields exceed the limit of {}", count, self.headers));
+        }
+        if bytes > self.header_bytes {
+            return Err(format!("{} bytes of header fields exceed the limit of {}", bytes, self.header_bytes));
+        }
+        Ok(())
+    }
+
+    /// Checks a request body against its limit
+    pub fn check_request_body(&self, length: usize) -> Result<(), String> {
+        if length > self.request_body {
+            return Err(format!("Request body of {} bytes exceeds the limit of {}", length, self.request_body));
+        }
+        Ok(())
+    }
+}
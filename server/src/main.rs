@@ -1,33 +1,63 @@
 use reqwest::{header::HeaderMap, header::HeaderName, header::HeaderValue};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
-use flate2::read::{DeflateDecoder, GzDecoder};
-use tokio::time::{Instant, Duration, timeout};
-use std::collections::HashMap;
-use std::io::Read;
+use tokio::time::{Instant, Duration};
+use warp::http::{Method, Response, StatusCode};
+use warp::Buf;
+use tokio_stream::{Stream, StreamExt};
+use warp::path::FullPath;
+use std::sync::Arc;
 use warp::Filter;
-use clap::Parser;
-use warp::Reply;
 use url::Url;
 
+use common::carrier::{Carrier, CarrierOptions, CarrierRequest, CarrierResponse};
+use common::config;
+use common::crypto::Psk;
+use common::encoding::Decoder;
+use common::structs::{decode_headers, encode_headers, ClientMessage, ErrorKind, ProxyRequest, ProxyResponse, ServerMessage};
+
+mod body;
+mod decoy;
+mod limits;
+mod mux;
+mod policy;
+mod retry;
+mod session;
 mod structs;
-use structs::{Cli, ProxyRequest, ProxyResponse};
+mod tls;
+mod udp;
+use body::BodyTable;
+use decoy::Decoy;
+use limits::Limits;
+use mux::MuxTable;
+use policy::{Denied, GuardedResolver, Policy};
+use retry::Failure;
+use session::{Opened, SessionTable};
+use structs::Cli;
+use tls::Certificates;
 
-const REQUEST_TIMEOUT: u64 = 30; // Request timeout in seconds
-const MAX_BODY_SIZE: usize = 10 * 1024 * 1024; // 10MB max body size
+const REQUEST_TIMEOUT: u64 = 30; // Default request timeout in seconds
+const MAX_BODY_SIZE: usize = 10 * 1024 * 1024; // 10MB max request body size
+const MAX_DECODED_SIZE: u64 = 1024 * 1024 * 1024; // 1GB max size a compressed response body may decode to
+const CARRIER_EXPANSION: usize = 8; // How many times its request body a carrier request may take up (png is the largest)
 const MAX_RETRIES: u32 = 3; // Maximum number of retries
 
-async fn display_banner(port: u16) {
+async fn display_banner(http_port: Option<u16>, https_port: Option<u16>) {
+    let url = |scheme: &str, host: &str, port: Option<u16>| {
+        port.map(|port| format!("{}://{}:{}", scheme, host, port)).unwrap_or_default()
+    };
+
     println!("      \x1b[1m\x1b[31m._______.\x1b[0m");
     println!("      \x1b[1m\x1b[31m| \\   / |\x1b[0m              Masquerade Proxy Server");
     println!("   .--\x1b[1m\x1b[31m|.O.|.O.|\x1b[32m______.\x1b[0m       v{}", env!("CARGO_PKG_VERSION"));
     println!("__). -\x1b[1m\x1b[31m| = | = |\x1b[32m/   \\ |\x1b[0m");
-    println!(">__)  \x1b[1m\x1b[31m(.'---`.)\x1b[32mQ.|.Q.|\x1b[0m--.    http://localhost:{}", port); 
-    println!("       \x1b[1m\x1b[31m\\\\___//\x1b[32m = | = |\x1b[0m-.(__  https://localhost:{}", port);
+    println!(">__)  \x1b[1m\x1b[31m(.'---`.)\x1b[32mQ.|.Q.|\x1b[0m--.    {}", url("http", "localhost", http_port)); 
+    println!("       \x1b[1m\x1b[31m\\\\___//\x1b[32m = | = |\x1b[0m-.(__  {}", url("https", "localhost", https_port));
 
     // Try to get and display public IP address if available
     if let Some(ip) = public_ip::addr().await {
-        println!("        \x1b[1m\x1b[31m`---'\x1b[32m( .---. )\x1b[0m (__<  http://{}:{}", ip, port);
-        println!("              \x1b[1m\x1b[32m\\\\.-.//\x1b[0m        https://{}:{}", ip, port);
+        let ip = ip.to_string();
+        println!("        \x1b[1m\x1b[31m`---'\x1b[32m( .---. )\x1b[0m (__<  {}", url("http", &ip, http_port));
+        println!("              \x1b[1m\x1b[32m\\\\.-.//\x1b[0m        {}", url("https", &ip, https_port));
     } else {
         println!("        \x1b[1m\x1b[31m`---'\x1b[32m( .---. )\x1b[0m (__<");
         println!("              \x1b[1m\x1b[32m\\\\.-.//\x1b[0m");
@@ -38,94 +68,368 @@ async fn display_banner(port: u16) {
 
 #[tokio::main]
 async fn main() {
-    // Parse command line arguments
-    let args = Cli::parse();
-    let port = args.port;
+    // Parse command line arguments, layered over the environment and any config file
+    let args: Cli = config::parse("MASQUERADE_SERVER");
+    let http_port = (!args.tls_only).then_some(args.port);
 
     // Display ASCII art banner with server information
-    display_banner(port).await;
+    display_banner(http_port, args.tls_port).await;
+
+    let certificates = match (args.tls_port, args.tls_cert, args.tls_key) {
+        (None, _, _) => None,
+        (Some(_), Some(cert), Some(key)) => Some(Certificates::load_or_generate(cert, key)),
+        (Some(_), _, _) => {
+            println!("⚠️ No --tls-cert given, serving HTTPS with a temporary self-signed certificate for localhost");
+            Some(Certificates::ephemeral())
+        }
+    };
+    let certificates = match certificates.transpose() {
+        Ok(certificates) => certificates.map(Arc::new),
+        Err(e) => {
+            println!("❌ Failed to set up TLS: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let policy = Arc::new(Policy {
+        allow_internal: args.allow_internal,
+        allow_cidrs: args.allow_cidrs,
+        deny_cidrs: args.deny_cidrs,
+        allow_domains: args.allow_domains.iter().map(|d| d.to_ascii_lowercase()).collect(),
+        deny_domains: args.deny_domains.iter().map(|d| d.to_ascii_lowercase()).collect(),
+        ports: args.allow_ports,
+        methods: args.allow_methods.iter().map(|m| m.trim().to_ascii_uppercase()).collect(),
+    });
+    if policy.allow_internal {
+        println!("⚠️ Clients may reach internal addresses (--allow-internal)");
+    }
+
+    let timeout = Duration::from_secs(args.request_timeout);
+    let pool_idle_timeout = Duration::from_secs(args.pool_idle_timeout);
+    let client = create_client(timeout, pool_idle_timeout, args.pool_max_idle, &policy, !args.no_follow_redirects);
+    if args.no_follow_redirects {
+        println!("↪️ Redirects are passed to the client instead of being followed");
+    }
+    let options = CarrierOptions {
+        cover_dir: args.cover_dir,
+        template_dir: args.template_dir,
+        // A request body travels base64 encoded, next to its headers
+        max_payload: Some(args.max_request_body.saturating_mul(4) / 3 + 1024 * 1024),
+    };
+    let carrier: Arc<dyn Carrier> = match args.carrier.build(&options) {
+        Ok(carrier) => Arc::from(carrier),
+        Err(e) => {
+            println!("❌ Failed to set up {} carrier: {}", args.carrier, e);
+            std::process::exit(1);
+        }
+    };
+    println!("🎭 Using {} carrier", args.carrier);
+
+    let sessions = match Psk::stretch(&args.psk).and_then(|psk| SessionTable::new(&psk)) {
+        Ok(sessions) => Arc::new(sessions),
+        Err(e) => {
+            println!("❌ Invalid pre-shared key: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let streams = Arc::new(MuxTable::new(policy.clone()));
+    let upstream = Arc::new(Upstream {
+        client,
+        policy,
+        limits: Limits {
+            request_body: args.max_request_body,
+            response_body: args.max_response_body,
+            decoded_body: args.max_decoded_body,
+            headers: args.max_headers,
+            header_bytes: args.max_header_size,
+        },
+        timeout,
+        retries: args.max_retries,
+        decode: !args.pass_encoded,
+        bodies: BodyTable::new(),
+    });
+
+    let decoy = match (args.decoy_dir, args.decoy_url) {
+        (Some(dir), _) => Decoy::from_dir(dir),
+        (_, Some(url)) => Decoy::from_url(&url),
+        _ => Ok(Decoy::Builtin),
+    };
+    let decoy = match decoy {
+        Ok(decoy) => Arc::new(decoy),
+        Err(e) => {
+            println!("❌ Failed to set up decoy: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Every request is handed to the carrier, which decides whether it holds a payload.
+    // No filters are layered on top, since anything they add would set the decoy apart.
+    // Carrier requests are limited too, since they are read whole before they can be authenticated
+    let carrier_limit = upstream.limits.request_body.saturating_mul(CARRIER_EXPANSION) + 1024 * 1024;
+    let refusing = decoy.clone();
+    let proxy = carrier_request(carrier_limit)
+        .and(warp::any().map(move || carrier.clone()))
+        .and(warp::any().map(move || sessions.clone()))
+        .and(warp::any().map(move || streams.clone()))
+        .and(warp::any().map(move || decoy.clone()))
+        .and(warp::any().map(move || upstream.clone()))
+        .then(handle_exchange)
+        .recover(move |rejection| refuse_oversized(rejection, refusing.clone()))
+        .unify();
+
+    let mut listeners = tokio::task::JoinSet::new();
+    if let Some(port) = http_port {
+        listeners.spawn(warp::serve(proxy.clone()).run((args.bind, port)));
+    }
+    if let (Some(port), Some(certificates)) = (args.tls_port, certificates) {
+        let listener = match tokio::net::TcpListener::bind((args.bind, port)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("❌ Failed to listen for HTTPS on port {}: {}", port, e);
+                std::process::exit(1);
+            }
+        };
+        tokio::spawn(certificates.clone().watch());
+        listeners.spawn(warp::serve(proxy).run_incoming(tls::incoming(listener, certificates)));
+    }
+    while listeners.join_next().await.is_some() {}
+}
+
+/// Collects the parts of an incoming request that a carrier may hide a payload in
+fn carrier_request(max_body: usize) -> impl Filter<Extract = (CarrierRequest,), Error = warp::Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::headers_cloned())
+        .map(|method: Method, path: FullPath, query: String, headers: warp::http::HeaderMap| {
+            CarrierRequest {
+                method: method.to_string(),
+                path: path.as_str().to_string(),
+                query: (!query.is_empty()).then_some(query),
+                headers: headers
+                    .iter()
+                    .map(|(k, v)| (k.as_str().to_string(), String::from_utf8_lossy(v.as_bytes()).to_string()))
+                    .collect(),
+                body: Vec::new(),
+            }
+        })
+        .and(warp::body::stream())
+        .and_then(move |request, stream| read_limited(request, stream, max_body))
+}
+
+/// Reads the request body, refusing it as too large as soon as it grows past
+/// `limit` bytes, whether or not it declared its length up front
+async fn read_limited(
+    mut request: CarrierRequest,
+    stream: impl Stream<Item = Result<impl Buf, warp::Error>>,
+    limit: usize,
+) -> Result<CarrierRequest, warp::Rejection> {
+    let mut stream = std::pin::pin!(stream);
+    while let Some(chunk) = stream.next().await {
+        let mut chunk = chunk.map_err(|_| warp::reject())?;
+        if request.body.len() + chunk.remaining() > limit {
+            request.body = Vec::new();
+            return Err(warp::reject::custom(Oversized(request)));
+        }
+        while chunk.has_remaining() {
+            let length = chunk.chunk().len();
+            request.body.extend_from_slice(chunk.chunk());
+            chunk.advance(length);
+        }
+    }
+    Ok(request)
+}
+
+/// The head of a carrier request larger than any client would send
+#[derive(Debug)]
+struct Oversized(CarrierRequest);
+
+impl warp::reject::Reject for Oversized {}
 
-    let client = create_client(REQUEST_TIMEOUT);
+/// Answers oversized requests as the decoy site would
+async fn refuse_oversized(rejection: warp::Rejection, decoy: Arc<Decoy>) -> Result<Response<Vec<u8>>, warp::Rejection> {
+    match rejection.find::<Oversized>() {
+        Some(Oversized(head)) => Ok(decoy.refuse(StatusCode::PAYLOAD_TOO_LARGE, head).await),
+        None => Err(rejection),
+    }
+}
 
-    // Set up the proxy route and start the server
-    let proxy = warp::path!("proxy")
-        .and(warp::query::<ProxyRequest>())
-        .and(warp::any().map(move || client.clone()))
-        .then(handle_proxy)
-        .with(warp::cors().allow_any_origin())
-        .with(warp::compression::gzip());
+/// What relaying requests to their origins needs
+struct Upstream {
+    client: reqwest::Client,
+    policy: Arc<Policy>,
+    limits: Limits,
+    timeout: Duration, // How long an origin may take to answer
+    retries: u32, // Most times a failed request is tried again
+    decode: bool, // Whether compressed response bodies are decoded before relaying
+    bodies: BodyTable, // Response bodies the clients are still fetching
+}
 
-    warp::serve(proxy).run(([127, 0, 0, 1], port)).await;
+/// Why a request was refused rather than answered with a response
+pub struct Refusal {
+    pub kind: ErrorKind,
+    pub message: String,
 }
 
-/// Create a configured reqwest client
-fn create_client(timeout_seconds: u64) -> reqwest::Client {
+impl Refusal {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Refusal {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Denied> for Refusal {
+    fn from(denied: Denied) -> Self {
+        Refusal::new(ErrorKind::Denied, denied.to_string())
+    }
+}
+
+impl From<Refusal> for ServerMessage {
+    fn from(refusal: Refusal) -> Self {
+        ServerMessage::Error {
+            kind: refusal.kind,
+            message: refusal.message,
+        }
+    }
+}
+
+/// Unwraps a carrier request, proxies it and wraps the result back up. Anything
+/// that is not an authenticated carrier request is answered by the decoy.
+async fn handle_exchange(
+    request: CarrierRequest,
+    carrier: Arc<dyn Carrier>,
+    sessions: Arc<SessionTable>,
+    streams: Arc<MuxTable>,
+    decoy: Arc<Decoy>,
+    upstream: Arc<Upstream>,
+) -> Response<Vec<u8>> {
+    let Ok(payload) = carrier.decode_request(&request) else {
+        return decoy.respond(&request).await;
+    };
+
+    let reply = match sessions.open(&payload) {
+        Ok(Opened::Handshake(hello)) => sessions.accept(&hello),
+        Ok(Opened::Message(session_id, sequence, message)) => {
+            let response = match message {
+                ClientMessage::Proxy(req) => match handle_proxy(req, &upstream).await {
+                    Ok(response) => ServerMessage::Proxy(response),
+                    Err(refusal) => refusal.into(),
+                },
+                ClientMessage::Body { stream } => upstream.bodies.next(stream).await,
+                ClientMessage::Mux { connection, frames, wait, resync } => {
+                    streams.exchange(connection, frames, wait, resync).await
+                }
+            };
+            sessions.seal(&session_id, sequence, &response)
+        }
+        Err(e) => {
+            println!("⛔ Rejected request, serving decoy: {}", e);
+            return decoy.respond(&request).await;
+        }
+    };
+
+    match reply.and_then(|sealed| carrier.encode_response(&sealed)) {
+        Ok(encoded) => into_response(encoded),
+        Err(e) => {
+            println!("❌ Failed to encode carrier response: {}", e);
+            decoy.refuse(StatusCode::INTERNAL_SERVER_ERROR, &request).await
+        }
+    }
+}
+
+/// Converts a carrier response into a warp response, with the same server
+/// headers as a decoy page
+fn into_response(encoded: CarrierResponse) -> Response<Vec<u8>> {
+    let mut builder = Response::builder().status(encoded.status);
+    for (name, value) in &encoded.headers {
+        builder = builder.header(name, value);
+    }
+    match builder.body(encoded.body) {
+        Ok(mut response) => {
+            decoy::server_headers(&mut response);
+            response
+        }
+        Err(_) => decoy::error_page(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Create a configured reqwest client. Every name it resolves and every redirect
+/// it follows goes through the target policy.
+fn create_client(
+    timeout: Duration,
+    pool_idle_timeout: Duration,
+    pool_max_idle: usize,
+    policy: &Arc<Policy>,
+    follow_redirects: bool,
+) -> reqwest::Client {
     reqwest::ClientBuilder::new()
-        .timeout(Duration::from_secs(timeout_seconds))
-        .pool_idle_timeout(Duration::from_secs(30))
-        .pool_max_idle_per_host(32)
+        .read_timeout(timeout) // Downloads may take as long as they like, but not stall
+        .dns_resolver(Arc::new(GuardedResolver(policy.clone())))
+        .redirect(policy.redirects(follow_redirects))
+        .no_proxy() // A proxy would resolve names itself, out of the policy's reach
+        .pool_idle_timeout(pool_idle_timeout)
+        .pool_max_idle_per_host(pool_max_idle)
         .tcp_keepalive(Duration::from_secs(60))
         .build()
         .expect("Failed to create HTTP client")
 }
 
-/// Main proxy request handler
-async fn handle_proxy(req: ProxyRequest, client: reqwest::Client) -> impl Reply {
+/// Main proxy request handler. Targets the policy forbids are refused rather
+/// than answered with a response.
+async fn handle_proxy(req: ProxyRequest, upstream: &Upstream) -> Result<ProxyResponse, Refusal> {
+    let Upstream { client, policy, limits, timeout, retries, decode, bodies } = upstream;
 
-    // Decode and validate the target URL
-    let target_url = match decode_base64(&req.target) {
-        Ok(url) => {
-            println!("🔄 Received proxy request: {:?} \n🎯 {}", {req.method.clone()}, url);
+    // Validate the target URL
+    let target_url = req.target;
+    println!("🔄 Received proxy request: {:?} \n🎯 {}", req.method, target_url);
 
-            if !Url::parse(&url).is_ok() {
-                println!("❌ Invalid target URL: {}", url);
-                return warp::reply::json(&ProxyResponse {
-                    status: 400,
-                    headers: HashMap::new(),
-                    body: format!("Invalid target URL: {}", url),
-                });
-            }
-            url
-        },
-        Err(e) => {
-            println!("❌ Failed to decode target URL: {}", e);
-            return warp::reply::json(&ProxyResponse {
-                status: 400,
-                headers: HashMap::new(),
-                body: format!("Invalid target URL encoding: {}", e),
-            });
-        }
-    };
-
-    // Decode and parse headers from base64 JSON string
-    let mut headers: HeaderMap = match decode_base64(&req.headers).and_then(|h| {
-        serde_json::from_str::<HashMap<String, String>>(&h).map_err(|e| e.to_string())
-    }) {
-        Ok(headers) => {
-            let mut header_map = HeaderMap::new();
-            // Convert each header key-value pair into proper HeaderName and HeaderValue types
-            for (key, value) in headers {
-                if let Ok(name) = HeaderName::from_bytes(key.as_bytes()) {
-                    if let Ok(value) = HeaderValue::from_str(&value) {
-                        header_map.insert(name, value);
-                    } else {
-                        println!("Invalid header value: {}", value);
-                    }
-                } else {
-                    println!("Invalid header name: {}", key);
-                }
-            }
-            header_map
-        }
+    let Ok(url) = Url::parse(&target_url) else {
+        println!("❌ Invalid target URL: {}", target_url);
+        return Ok(error_response(400, format!("Invalid target URL: {}", target_url)));
+    };
+    if let Err(denied) = policy.check_url(&url) {
+        println!("⛔ {}", denied);
+        return Err(denied.into());
+    }
+
+    // Any valid method token is forwarded, WebDAV and extension methods included
+    let Ok(method) = reqwest::Method::from_bytes(req.method.as_bytes()) else {
+        println!("❌ Invalid method: {}", req.method);
+        return Ok(error_response(400, format!("Invalid method: {}", req.method)));
+    };
+    if let Err(denied) = policy.check_method(method.as_str()) {
+        println!("⛔ {}", denied);
+        return Err(denied.into());
+    }
+
+    let pairs = match decode_headers(&req.headers) {
+        Ok(pairs) => pairs,
         Err(e) => {
-            println!("❌ Failed to decode headers: {}", e);
-            return warp::reply::json(&ProxyResponse {
-                status: 400,
-                headers: HashMap::new(),
-                body: format!("Invalid headers encoding: {}", e),
-            });
+            println!("❌ {}", e);
+            return Ok(error_response(400, e));
         }
     };
+    if let Err(e) = limits.check_headers(pairs.iter().map(|(name, value)| (name.as_str(), value.as_slice()))) {
+        println!("❌ Request refused: {}", e);
+        return Err(Refusal::new(ErrorKind::RequestTooLarge, e));
+    }
+
+    // Convert each header into proper HeaderName and HeaderValue types, keeping repeats
+    let mut headers = HeaderMap::new();
+    for (key, value) in pairs {
+        if let Ok(name) = HeaderName::from_bytes(key.as_bytes()) {
+            if let Ok(value) = HeaderValue::from_bytes(&value) {
+                headers.append(name, value);
+            } else {
+                println!("Invalid header value for {}", key);
+            }
+        } else {
+            println!("Invalid header name: {}", key);
+        }
+    }
 
     headers.remove(reqwest::header::HOST);
     headers.remove(reqwest::header::CONNECTION);
@@ -136,56 +440,42 @@ async fn handle_proxy(req: ProxyRequest, client: reqwest::Client) -> impl Reply
         HeaderValue::from_static("no-cache"),
     );
 
-    // Create HTTP client and handle request based on method
-    
-    let body = decode_base64(&req.body.unwrap_or_default()).unwrap_or_default();
+    // The body is passed on byte for byte; one that does not decode is refused, not dropped
+    let body = match req.body.as_deref().map(|body| BASE64.decode(body)).transpose() {
+        Ok(body) => body.unwrap_or_default(),
+        Err(e) => {
+            println!("❌ Undecodable request body: {}", e);
+            return Ok(error_response(400, format!("Undecodable request body: {}", e)));
+        }
+    };
+    if let Err(e) = limits.check_request_body(body.len()) {
+        println!("❌ Request refused: {}", e);
+        return Err(Refusal::new(ErrorKind::RequestTooLarge, e));
+    }
     let start_time = Instant::now();
 
-    let request = match req.method.as_str() {
-        "GET" => client
-            .get(&target_url)
-            .headers(headers.clone()),
-        "POST" => client
-            .post(&target_url)
-            .headers(headers.clone())
-            .body(body),
-        "PUT" => client
-            .put(&target_url)
-            .headers(headers.clone())
-            .body(body),
-        "DELETE" => client
-            .delete(&target_url)
-            .headers(headers.clone()),
-        _ => {
-            println!("❌ Unsupported method: {}", req.method);
-            return warp::reply::json(&ProxyResponse {
-                status: 400,
-                headers: HashMap::new(),
-                body: format!("Unsupported method: {}", req.method),
-            });
-        }
-    };
-
-    let response = match timeout(
-        Duration::from_secs(REQUEST_TIMEOUT), // REQUEST_TIMEOUT should be defined as a constant
-        request.send()
-    ).await {
-        Ok(Ok(response)) => response,  // Request completed successfully
-        Ok(Err(e)) => {  // Request failed (e.g. network error)
+    // A body is sent with whatever method it came with; an empty one adds nothing
+    let mut request = client.request(method.clone(), &target_url).headers(headers.clone());
+    if !body.is_empty() {
+        request = request.body(body);
+    }
+
+    // Attempts that fail in passing are retried, all within one deadline
+    let deadline = start_time + *timeout;
+    let response = match retry::send(request, *retries, deadline).await {
+        Ok(response) => response,  // Request completed successfully
+        Err(Failure::Error(e)) => {  // Request failed (e.g. network error)
+            // The target, or a redirect from it, led somewhere the policy forbids
+            if let Some(denied) = Denied::cause_of(&e) {
+                println!("⛔ {}", denied);
+                return Err(denied.into());
+            }
             println!("❌ Request failed: {}", e);
-            return warp::reply::json(&ProxyResponse {
-                status: 500,
-                headers: HashMap::new(),
-                body: format!("Request failed: {}", e),
-            });
+            return Ok(error_response(500, format!("Request failed: {}", e)));
         },
-        Err(_) => {  // Timeout occurred
+        Err(Failure::TimedOut) => {
             println!("❌ Request timed out");
-            return warp::reply::json(&ProxyResponse {
-                status: 504,  // Gateway Timeout
-                headers: HashMap::new(),
-                body: "Request timed out".to_string(),
-            });
+            return Ok(error_response(504, "Request timed out"));
         }
     };
 
@@ -194,51 +484,199 @@ async fn handle_proxy(req: ProxyRequest, client: reqwest::Client) -> impl Reply
         start_time.elapsed().as_secs_f64()
     );
 
-    // Handle response headers and body decompression
-    let mut headers = response.headers().clone();
-    let content_encoding = response.headers().get(reqwest::header::CONTENT_ENCODING);
-    let mut decompressed_data = Vec::new();
+    // hyper only keeps the reason phrase when it is not the standard one for the status
+    let status = response.status();
+    let reason = response
+        .extensions()
+        .get::<hyper::ext::ReasonPhrase>()
+        .and_then(|reason| std::str::from_utf8(reason.as_bytes()).ok())
+        .or(status.canonical_reason())
+        .map(str::to_string);
+    println!("📬 Origin answered {} {}", status.as_u16(), reason.as_deref().unwrap_or(""));
 
-    // Handle different content encoding types (gzip, deflate)
-    match content_encoding.and_then(|v| v.to_str().ok()) {
-        Some("gzip") => {
-            let compressed_data = response.bytes().await.unwrap();
-            let mut decoder = GzDecoder::new(&compressed_data[..]);
-            decoder.read_to_end(&mut decompressed_data).unwrap();
-        }
-        Some("deflate") => {
-            let compressed_data = response.bytes().await.unwrap();
-            let mut decoder = DeflateDecoder::new(&compressed_data[..]);
-            decoder.read_to_end(&mut decompressed_data).unwrap();
+    let origin_headers = response.headers().iter().map(|(name, value)| (name.as_str(), value.as_bytes()));
+    if let Err(e) = limits.check_headers(origin_headers) {
+        println!("❌ Response refused: {}", e);
+        return Err(Refusal::new(ErrorKind::ResponseTooLarge, e));
+    }
+
+    // Header fields the origin sent that describe the body as it travelled, not as relayed
+    let mut dropped = vec![reqwest::header::TRANSFER_ENCODING];
+
+    // Some responses never carry a body; their headers describe the body a GET
+    // would have had, so they are passed on as they are
+    let bodiless = method == reqwest::Method::HEAD
+        || status.is_informational()
+        || status == reqwest::StatusCode::NO_CONTENT
+        || status == reqwest::StatusCode::NOT_MODIFIED;
+    if bodiless {
+        return Ok(ProxyResponse {
+            status: status.as_u16(),
+            reason,
+            headers: relayed_headers(response.headers(), &dropped, None),
+            body: String::new(),
+            stream: None,
+        });
+    }
+
+    // A body declared too large is refused before any of it is read
+    if let (Some(length), Some(limit)) = (response.content_length(), limits.response_body) {
+        if length > limit {
+            let message = format!("Response body of {} bytes exceeds the limit of {}", length, limit);
+            println!("❌ Response refused: {}", message);
+            return Err(Refusal::new(ErrorKind::ResponseTooLarge, message));
         }
-        _ => {
-            decompressed_data = response.bytes().await.unwrap().to_vec();
+    }
+
+    // Bodies are decoded unless told otherwise or using a coding we cannot undo, in
+    // which case they pass through with their Content-Encoding still in place
+    let content_encoding = response
+        .headers()
+        .get_all(reqwest::header::CONTENT_ENCODING)
+        .iter()
+        .map(|v| v.to_str().unwrap_or("unknown"))
+        .collect::<Vec<_>>()
+        .join(",");
+    let decoder = match Decoder::new(&content_encoding).filter(|decoder| *decode || decoder.is_identity()) {
+        Some(decoder) => decoder,
+        None => {
+            println!("📦 Passing the {} response body through undecoded", content_encoding);
+            Decoder::identity()
         }
+    };
+
+    // A decoded body's length is only known once it has all been read
+    if !decoder.is_identity() {
+        dropped.push(reqwest::header::CONTENT_ENCODING);
+        dropped.push(reqwest::header::CONTENT_LENGTH);
     }
 
-    // Clean up response headers
-    headers.remove(reqwest::header::CONTENT_ENCODING);
-    headers.remove(reqwest::header::TRANSFER_ENCODING);
-    headers.insert(
-        reqwest::header::CONTENT_LENGTH,
-        HeaderValue::from_str(&decompressed_data.clone().len().to_string()).unwrap(),
-    );
+    // Headers are taken now, since the body takes the response with it
+    let origin_headers = response.headers().clone();
+
+    // Large bodies are relayed a piece at a time as the client asks for them
+    let (body, stream) = match bodies.start(response, decoder, limits).await {
+        Ok(started) => started,
+        Err(refusal) => {
+            println!("❌ {}", refusal.message);
+            return Err(refusal);
+        }
+    };
+    let content_length = match stream {
+        None => Some(body.len()),
+        Some(_) => {
+            println!("🚰 Streaming the response body");
+            None
+        }
+    };
 
     // Encode response body to base64 and return
-    let base64_body = BASE64.encode(&decompressed_data);
-    let headers = headers.iter().map(|(k, v)| {(k.as_str().to_string(),v.to_str().unwrap_or_default().to_string(),)}).collect();
+    let base64_body = BASE64.encode(&body);
+    let headers = relayed_headers(&origin_headers, &dropped, content_length);
 
-    warp::reply::json(&ProxyResponse {
-        status: 200,
-        headers: headers,
+    Ok(ProxyResponse {
+        status: status.as_u16(),
+        reason,
+        headers,
         body: base64_body,
+        stream,
     })
 }
 
-/// Decodes a base64 string into a UTF-8 string
-fn decode_base64(input: &str) -> Result<String, String> {
-    BASE64
-        .decode(input)
-        .map_err(|e| e.to_string())
-        .and_then(|bytes| String::from_utf8(bytes).map_err(|e| e.to_string()))
-}
\ No newline at end of file
+/// The origin's header fields as they are passed on to the client, every value
+/// kept byte for byte. The HTTP client has already grouped repeats of a name at
+/// the place the name first appeared, so how the origin interleaved different
+/// names is lost, but the order of the names and of each name's values is kept.
+/// A `content_length` takes the place of the origin's, or goes last if it sent none.
+fn relayed_headers(origin: &HeaderMap, dropped: &[HeaderName], content_length: Option<usize>) -> common::structs::Headers {
+    let length = content_length.map(HeaderValue::from);
+    let mut placed = false;
+    let mut fields = Vec::new();
+    for (name, value) in origin.iter().filter(|(name, _)| !dropped.contains(name)) {
+        match &length {
+            Some(length) if name == reqwest::header::CONTENT_LENGTH => {
+                if !placed {
+                    fields.push((name.as_str(), length.as_bytes()));
+                    placed = true;
+                }
+            }
+            _ => fields.push((name.as_str(), value.as_bytes())),
+        }
+    }
+    if let (Some(length), false) = (&length, placed) {
+        fields.push((reqwest::header::CONTENT_LENGTH.as_str(), length.as_bytes()));
+    }
+    encode_headers(fields)
+}
+
+/// A plain text response explaining why a request could not be carried out
+fn error_response(status: u16, message: impl Into<String>) -> ProxyResponse {
+    let message = message.into();
+    ProxyResponse {
+        status,
+        reason: None,
+        headers: encode_headers([("content-type", b"text/plain; charset=utf-8".as_slice())]),
+        body: BASE64.encode(message.as_bytes()),
+        stream: None,
+    }
+}
+#[cfg(test)]
+mod tests {
+    use reqwest::header::{CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
+
+    use super::*;
+
+    fn origin(fields: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in fields {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn names(headers: &common::structs::Headers) -> Vec<&str> {
+        headers.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn carrier_replies_carry_the_decoy_server_headers() {
+        let encoded = CarrierResponse {
+            status: 200,
+            headers: vec![("content-type".to_string(), "image/png".to_string())],
+            body: Vec::new(),
+        };
+        let decoy = decoy::error_page(StatusCode::NOT_FOUND);
+        let server = warp::http::header::SERVER;
+        assert_eq!(into_response(encoded).headers().get(&server), decoy.headers().get(&server));
+    }
+
+    #[test]
+    fn header_order_survives_dropped_fields() {
+        let headers = origin(&[
+            ("content-encoding", "gzip"),
+            ("date", "Sun, 18 Oct 2026 10:00:00 GMT"),
+            ("content-length", "120"),
+            ("set-cookie", "a=1"),
+            ("transfer-encoding", "chunked"),
+            ("set-cookie", "b=2"),
+            ("etag", "\"x\""),
+        ]);
+        let relayed = relayed_headers(&headers, &[TRANSFER_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH], Some(640));
+        assert_eq!(names(&relayed), ["date", "set-cookie", "set-cookie", "etag", "content-length"]);
+        let values = decode_headers(&relayed).unwrap();
+        assert_eq!(values[1].1, b"a=1");
+        assert_eq!(values[2].1, b"b=2");
+        assert_eq!(values[4].1, b"640");
+    }
+
+    #[test]
+    fn a_new_length_takes_the_place_of_the_old() {
+        let headers = origin(&[("date", "today"), ("content-length", "5"), ("etag", "\"x\"")]);
+        let relayed = relayed_headers(&headers, &[TRANSFER_ENCODING], Some(7));
+        assert_eq!(names(&relayed), ["date", "content-length", "etag"]);
+        assert_eq!(decode_headers(&relayed).unwrap()[1].1, b"7");
+        // Streamed bodies keep whatever length the origin declared
+        let relayed = relayed_headers(&headers, &[TRANSFER_ENCODING], None);
+        assert_eq!(decode_headers(&relayed).unwrap()[1].1, b"5");
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration, Instant};
+
+use common::mux::{Endpoint, Frame, Incoming};
+use common::structs::ServerMessage;
+
+use crate::policy::Policy;
+use crate::udp;
+
+const CONNECT_TIMEOUT: u64 = 10; // Seconds allowed to reach a stream's target
+const CONNECTION_IDLE_TIMEOUT: u64 = 5 * 60; // Clients silent for this long lose their streams (seconds)
+const POLL_WAIT: u64 = 1000; // How long a poll without new data waits for the targets (milliseconds)
+
+struct Connection {
+    endpoint: Arc<Endpoint>,
+    last_seen: Instant,
+}
+
+/// The multiplexed stream buffers and UDP associations the server keeps for each client. They are keyed
+/// by a client-chosen connection id rather than the session, so streams survive
+/// session rotation.
+pub struct MuxTable {
+    connections: Mutex<HashMap<u64, Connection>>,
+    policy: Arc<Policy>, // Where streams and datagrams may go
+}
+
+impl MuxTable {
+    pub fn new(policy: Arc<Policy>) -> Self {
+        MuxTable {
+            connections: Mutex::new(HashMap::new()),
+            policy,
+        }
+    }
+
+    /// Applies a batch of frames from a client and returns the frames waiting for it.
+    /// Polls that carried nothing new are held briefly so the client does not spin.
+    pub async fn exchange(&self, connection: u64, frames: Vec<Frame>, wait: bool, resync: bool) -> ServerMessage {
+        let endpoint = self.endpoint(connection);
+
+        // Datagrams for associations not yet running start one, in the order they came
+        let mut unclaimed: BTreeMap<u32, Vec<(String, Vec<u8>)>> = BTreeMap::new();
+        for incoming in endpoint.receive(frames) {
+            match incoming {
+                Incoming::Open { stream, target } => {
+                    tokio::spawn(dial(endpoint.clone(), self.policy.clone(), stream, target));
+                }
+                Incoming::Datagram { association, peer, data } => {
+                    unclaimed.entry(association).or_default().push((peer, data));
+                }
+                Incoming::Dissociate { .. } => {} // Its relay stops once the endpoint drops its channel
+            }
+        }
+        for (association, initial) in unclaimed {
+            let from_client = endpoint.associate(association);
+            tokio::spawn(udp::relay(endpoint.clone(), self.policy.clone(), association, from_client, initial));
+        }
+        if resync {
+            endpoint.resync();
+        }
+
+        if wait && !endpoint.has_outbound() {
+            let _ = timeout(Duration::from_millis(POLL_WAIT), endpoint.outbound_ready()).await;
+        }
+        ServerMessage::Mux { frames: endpoint.take_frames() }
+    }
+
+    fn endpoint(&self, connection: u64) -> Arc<Endpoint> {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|_, c| {
+            let alive = c.last_seen.elapsed() < Duration::from_secs(CONNECTION_IDLE_TIMEOUT);
+            if !alive {
+                c.endpoint.shutdown();
+            }
+            alive
+        });
+
+        let entry = connections.entry(connection).or_insert_with(|| Connection {
+            endpoint: Arc::new(Endpoint::new(true)),
+            last_seen: Instant::now(),
+        });
+        entry.last_seen = Instant::now();
+        entry.endpoint.clone()
+    }
+}
+
+/// Connects a stream the client opened to its target and relays it until it closes
+async fn dial(endpoint: Arc<Endpoint>, policy: Arc<Policy>, stream: u32, target: String) {
+    println!("🔐 Opening tunnel to {}", target);
+
+    let addresses = match policy.resolve(&target).await {
+        Ok(addresses) => addresses,
+        Err(e) => return refuse(&endpoint, stream, e),
+    };
+    let socket = match timeout(Duration::from_secs(CONNECT_TIMEOUT), TcpStream::connect(&addresses[..])).await {
+        Ok(Ok(socket)) => socket,
+        Ok(Err(e)) => return refuse(&endpoint, stream, format!("Failed to connect to {}: {}", target, e)),
+        Err(_) => return refuse(&endpoint, stream, format!("Timed out connecting to {}", target)),
+    };
+
+    endpoint.accept(stream);
+    println!("✅ Tunnel open ({} active)", endpoint.stream_count());
+
+    let (from_target, to_target) = endpoint.attach(stream, socket).await;
+    println!("🔄 Tunnel to {} closed ({} bytes up, {} bytes down)", target, to_target, from_target);
+}
+
+fn refuse(endpoint: &Endpoint, stream: u32, message: String) {
+    println!("❌ {}", message);
+    endpoint.refuse(stream, &message);
+}
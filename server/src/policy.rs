@@ -0,0 +1,486 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use tokio::net::lookup_host;
+use url::{Host, Url};
+
+use common::structs::DENIED;
+
+const MAX_REDIRECTS: usize = 10;
+
+// Addresses that lead into the server's own machine or network rather than the
+// internet, refused unless explicitly allowed
+const INTERNAL_V4: &[(Ipv4Addr, u8)] = &[
+    (Ipv4Addr::new(0, 0, 0, 0), 8),       // "This" network
+    (Ipv4Addr::new(10, 0, 0, 0), 8),      // Private
+    (Ipv4Addr::new(100, 64, 0, 0), 10),   // Carrier-grade NAT
+    (Ipv4Addr::new(127, 0, 0, 0), 8),     // Loopback
+    (Ipv4Addr::new(169, 254, 0, 0), 16),  // Link-local, including cloud metadata services
+    (Ipv4Addr::new(172, 16, 0, 0), 12),   // Private
+    (Ipv4Addr::new(192, 0, 0, 0), 24),    // Protocol assignments
+    (Ipv4Addr::new(192, 168, 0, 0), 16),  // Private
+    (Ipv4Addr::new(198, 18, 0, 0), 15),   // Benchmarking
+    (Ipv4Addr::new(224, 0, 0, 0), 4),     // Multicast
+    (Ipv4Addr::new(240, 0, 0, 0), 4),     // Reserved and broadcast
+];
+const INTERNAL_V6: &[(Ipv6Addr, u8)] = &[
+    (Ipv6Addr::UNSPECIFIED, 128),
+    (Ipv6Addr::LOCALHOST, 128),
+    (Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, 0), 64),  // Discard-only
+    (Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 0), 32), // Teredo, which tunnels through IPv4
+    (Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7),  // Unique local
+    (Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10), // Link-local
+    (Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0), 10), // Site-local (deprecated, but still routed by some networks)
+    (Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0), 8),  // Multicast
+];
+
+/// Why a target was refused
+#[derive(Clone, Debug)]
+pub struct Denied(String);
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", DENIED, self.0)
+    }
+}
+
+impl std::error::Error for Denied {}
+
+impl Denied {
+    /// Finds a denial among the causes of a failed request
+    pub fn cause_of(error: &(dyn std::error::Error + 'static)) -> Option<Denied> {
+        let mut cause = Some(error);
+        while let Some(error) = cause {
+            if let Some(denied) = error.downcast_ref::<Denied>() {
+                return Some(denied.clone());
+            }
+            cause = error.source();
+        }
+        None
+    }
+}
+
+/// An address block, written as 10.0.0.0/8 or fd00::/8 (a bare address is a block of one)
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether the block holds an address, as written or as the IPv4 address it embeds
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.holds(ip) || self.holds(canonical(ip))
+    }
+
+    fn holds(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => in_block(network.to_bits() as u128, ip.to_bits() as u128, self.prefix, 32),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => in_block(network.to_bits(), ip.to_bits(), self.prefix, 128),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = address.trim().parse().map_err(|_| format!("Invalid address in {}", s))?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().ok().filter(|&p| p <= bits).ok_or(format!("Invalid prefix length in {}", s))?,
+            None => bits,
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+/// A port or an inclusive range of them, written as 443 or 8000-8999
+#[derive(Clone, Copy, Debug)]
+pub struct Ports {
+    first: u16,
+    last: u16,
+}
+
+impl FromStr for Ports {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |port: &str| port.trim().parse::<u16>().map_err(|_| format!("Invalid port in {}", s));
+        let (first, last) = match s.split_once('-') {
+            Some((first, last)) => (parse(first)?, parse(last)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if first > last {
+            return Err(format!("Port range {} is backwards", s));
+        }
+        Ok(Ports { first, last })
+    }
+}
+
+/// Decides which destinations clients may make the server connect to. Names are
+/// checked before they are resolved and every address they resolve to is
+/// checked again, so a name cannot smuggle a client into the server's network.
+pub struct Policy {
+    pub allow_internal: bool,       // Whether internal addresses are reachable at all
+    pub allow_cidrs: Vec<Cidr>,     // Internal blocks reachable anyway
+    pub deny_cidrs: Vec<Cidr>,      // Blocks never reachable, internal or not
+    pub allow_domains: Vec<String>, // If any, the only names reachable (lowercase)
+    pub deny_domains: Vec<String>,  // Names never reachable (lowercase)
+    pub ports: Vec<Ports>,          // If any, the only ports reachable
+    pub methods: Vec<String>,       // If any, the only methods forwarded (uppercase)
+}
+
+impl Policy {
+    /// Checks a URL about to be fetched, before any name in it is resolved
+    pub fn check_url(&self, url: &Url) -> Result<(), Denied> {
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(Denied(format!("scheme {} is not allowed", url.scheme())));
+        }
+        let port = url.port_or_known_default().unwrap_or(0);
+        match url.host() {
+            Some(Host::Domain(name)) => self.check_name(name, port),
+            Some(Host::Ipv4(ip)) => self.check_address(IpAddr::V4(ip), port),
+            Some(Host::Ipv6(ip)) => self.check_address(IpAddr::V6(ip), port),
+            None => Err(Denied("URL has no host".to_string())),
+        }
+    }
+
+    /// Checks the method of a request about to be forwarded
+    pub fn check_method(&self, method: &str) -> Result<(), Denied> {
+        if !self.methods.is_empty() && !self.methods.iter().any(|m| m == method) {
+            return Err(Denied(format!("method {} is not allowed", method)));
+        }
+        Ok(())
+    }
+
+    /// Resolves a host:port target for a tunnel or datagram, keeping only the
+    /// addresses the policy allows
+    pub async fn resolve(&self, target: &str) -> Result<Vec<SocketAddr>, String> {
+        let (host, port) = target
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host.trim_start_matches('[').trim_end_matches(']'), port.parse().ok()?)))
+            .ok_or(format!("Invalid target {}", target))?;
+
+        let denied = match host.parse::<IpAddr>() {
+            Ok(ip) => self.check_address(ip, port).err(),
+            Err(_) => self.check_name(host, port).err(),
+        };
+        if let Some(denied) = denied {
+            return Err(denied.to_string());
+        }
+
+        let resolved = lookup_host((host, port))
+            .await
+            .map_err(|e| format!("Failed to look up {}: {}", host, e))?;
+        self.filter(resolved, host).map_err(|denied| denied.to_string())
+    }
+
+    fn check_name(&self, name: &str, port: u16) -> Result<(), Denied> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if self.deny_domains.iter().any(|domain| matches_domain(&name, domain)) {
+            return Err(Denied(format!("{} is on the deny list", name)));
+        }
+        if !self.allow_domains.is_empty() && !self.allow_domains.iter().any(|domain| matches_domain(&name, domain)) {
+            return Err(Denied(format!("{} is not on the allow list", name)));
+        }
+        self.check_port(port)
+    }
+
+    /// Checks a target given as an address rather than a name
+    fn check_address(&self, ip: IpAddr, port: u16) -> Result<(), Denied> {
+        // A domain allow list would mean nothing if bare addresses got around it
+        let listed = self.allow_cidrs.iter().any(|cidr| cidr.contains(ip));
+        if !self.allow_domains.is_empty() && !listed {
+            return Err(Denied(format!("{} is not on the allow list", ip)));
+        }
+        self.check_port(port)?;
+        self.check_ip(ip)
+    }
+
+    fn check_port(&self, port: u16) -> Result<(), Denied> {
+        if port == 0 || (!self.ports.is_empty() && !self.ports.iter().any(|p| (p.first..=p.last).contains(&port))) {
+            return Err(Denied(format!("port {} is not allowed", port)));
+        }
+        Ok(())
+    }
+
+    fn check_ip(&self, ip: IpAddr) -> Result<(), Denied> {
+        if self.deny_cidrs.iter().any(|cidr| cidr.contains(ip)) {
+            return Err(Denied(format!("{} is in a denied range", ip)));
+        }
+        if !self.allow_internal && is_internal(ip) && !self.allow_cidrs.iter().any(|cidr| cidr.contains(ip)) {
+            return Err(Denied(format!("{} is an internal address", ip)));
+        }
+        Ok(())
+    }
+
+    /// Keeps the allowed addresses a name resolved to, or explains why there are none
+    fn filter(&self, addresses: impl Iterator<Item = SocketAddr>, name: &str) -> Result<Vec<SocketAddr>, Denied> {
+        let mut allowed = Vec::new();
+        let mut denied = None;
+        for address in addresses {
+            match self.check_ip(address.ip()) {
+                Ok(()) => allowed.push(address),
+                Err(e) => denied = Some(e),
+            }
+        }
+        match (allowed.is_empty(), denied) {
+            (true, Some(denied)) => Err(Denied(format!("{} resolves to a forbidden address: {}", name, denied.0))),
+            _ => Ok(allowed),
+        }
+    }
+
+    /// A redirect policy that applies this policy to every hop, or that returns
+    /// redirects untouched if they are not to be followed
+    pub fn redirects(self: &Arc<Self>, follow: bool) -> reqwest::redirect::Policy {
+        if !follow {
+            return reqwest::redirect::Policy::none();
+        }
+        let policy = self.clone();
+        reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+            match policy.check_url(attempt.url()) {
+                Ok(()) => attempt.follow(),
+                Err(denied) => attempt.error(denied),
+            }
+        })
+    }
+}
+
+/// Resolves names for the HTTP client through the policy. The client connects to
+/// exactly the addresses checked here, so a name that changes its answer between
+/// the check and the connection (DNS rebinding) gets nowhere.
+pub struct GuardedResolver(pub Arc<Policy>);
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.0.clone();
+        Box::pin(async move {
+            let resolved = lookup_host((name.as_str(), 0)).await?;
+            let allowed = policy.filter(resolved, name.as_str())?;
+            let addresses: Addrs = Box::new(allowed.into_iter());
+            Ok(addresses)
+        })
+    }
+}
+
+/// Whether `name` is `domain` or one of its subdomains
+fn matches_domain(name: &str, domain: &str) -> bool {
+    let domain = domain.trim_start_matches("*.").trim_start_matches('.');
+    name == domain || name.strip_suffix(domain).is_some_and(|rest| rest.ends_with('.'))
+}
+
+/// Whether an address is internal, as written or as the IPv4 address it embeds
+fn is_internal(ip: IpAddr) -> bool {
+    [ip, canonical(ip)].into_iter().any(|ip| match ip {
+        IpAddr::V4(ip) => INTERNAL_V4
+            .iter()
+            .any(|&(network, prefix)| in_block(network.to_bits() as u128, ip.to_bits() as u128, prefix, 32)),
+        IpAddr::V6(ip) => INTERNAL_V6
+            .iter()
+            .any(|&(network, prefix)| in_block(network.to_bits(), ip.to_bits(), prefix, 128)),
+    })
+}
+
+/// IPv6 addresses that carry an IPv4 address reach it, so they are checked as that
+/// address: mapped (::ffff:a.b.c.d), IPv4-compatible (::a.b.c.d), NAT64
+/// (64:ff9b::a.b.c.d), 6to4 (2002:aabb:ccdd::) and Teredo, whose last 32 bits
+/// are the client's address inverted (2001:0:...:!aabb:!ccdd)
+fn canonical(ip: IpAddr) -> IpAddr {
+    let IpAddr::V6(v6) = ip else {
+        return ip;
+    };
+    let low = Ipv4Addr::from_bits(v6.to_bits() as u32);
+    match v6.segments() {
+        [0, 0, 0, 0, 0, 0xffff, _, _] => IpAddr::V4(low),
+        // :: and ::1 are IPv6's own unspecified and loopback addresses
+        [0, 0, 0, 0, 0, 0, _, _] if v6.to_bits() > 1 => IpAddr::V4(low),
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => IpAddr::V4(low),
+        [0x2002, high, low, ..] => IpAddr::V4(Ipv4Addr::from_bits((high as u32) << 16 | low as u32)),
+        [0x2001, 0, ..] => IpAddr::V4(Ipv4Addr::from_bits(!low.to_bits())),
+        _ => ip,
+    }
+}
+
+fn in_block(network: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+    let shift = (bits - prefix) as u32;
+    shift >= bits as u32 || (network >> shift) == (ip >> shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        Policy {
+            allow_internal: false,
+            allow_cidrs: Vec::new(),
+            deny_cidrs: Vec::new(),
+            allow_domains: Vec::new(),
+            deny_domains: Vec::new(),
+            ports: Vec::new(),
+            methods: Vec::new(),
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn url(s: &str) -> Url {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidrs_parse_and_match() {
+        let block: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(block.contains(ip("10.1.255.255")));
+        assert!(!block.contains(ip("10.2.0.0")));
+        let host: Cidr = " 192.0.2.7 ".parse().unwrap();
+        assert!(host.contains(ip("192.0.2.7")) && !host.contains(ip("192.0.2.8")));
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("8.8.8.8")) && !everything.contains(ip("2001:db8::1")));
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:ffff::1")) && !v6.contains(ip("2001:db9::1")));
+
+        for invalid in ["10.0.0/8", "10.0.0.0/33", "::/129", "10.0.0.0/x", "example.com"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn ports_parse_and_match() {
+        assert!(policy().check_port(0).is_err());
+        let listed = Policy {
+            ports: vec!["443".parse().unwrap(), "8000-8999".parse().unwrap()],
+            ..policy()
+        };
+        for allowed in [443, 8000, 8999] {
+            assert!(listed.check_port(allowed).is_ok(), "{}", allowed);
+        }
+        for denied in [0, 80, 7999, 9000] {
+            assert!(listed.check_port(denied).is_err(), "{}", denied);
+        }
+        assert!("9000-8000".parse::<Ports>().is_err());
+        assert!("65536".parse::<Ports>().is_err());
+    }
+
+    #[test]
+    fn domains_match_themselves_and_their_subdomains() {
+        assert!(matches_domain("example.com", "example.com"));
+        assert!(matches_domain("www.example.com", "example.com"));
+        assert!(matches_domain("www.example.com", "*.example.com"));
+        assert!(matches_domain("a.b.example.com", ".example.com"));
+        assert!(!matches_domain("badexample.com", "example.com"));
+        assert!(!matches_domain("example.com.evil", "example.com"));
+    }
+
+    #[test]
+    fn domain_lists_are_applied_to_urls() {
+        let listed = Policy {
+            allow_domains: vec!["example.com".to_string()],
+            deny_domains: vec!["secret.example.com".to_string()],
+            ..policy()
+        };
+        assert!(listed.check_url(&url("https://WWW.Example.com./")).is_ok());
+        assert!(listed.check_url(&url("https://api.secret.example.com/")).is_err());
+        assert!(listed.check_url(&url("https://example.org/")).is_err());
+        // Bare addresses cannot get around an allow list
+        assert!(listed.check_url(&url("http://93.184.216.34/")).is_err());
+        assert!(listed.check_url(&url("ftp://example.com/")).is_err());
+    }
+
+    #[test]
+    fn internal_addresses_need_allowing() {
+        assert!(policy().check_url(&url("http://127.0.0.1:8080/")).is_err());
+        assert!(policy().check_url(&url("http://[fe80::1]/")).is_err());
+        assert!(policy().check_url(&url("http://8.8.8.8/")).is_ok());
+
+        let open = Policy { allow_internal: true, ..policy() };
+        assert!(open.check_url(&url("http://192.168.0.1/")).is_ok());
+        let listed = Policy {
+            allow_cidrs: vec!["192.168.0.0/24".parse().unwrap()],
+            ..policy()
+        };
+        assert!(listed.check_url(&url("http://192.168.0.1/")).is_ok());
+        assert!(listed.check_url(&url("http://192.168.1.1/")).is_err());
+        let denied = Policy {
+            allow_internal: true,
+            deny_cidrs: vec!["192.168.0.0/16".parse().unwrap()],
+            ..policy()
+        };
+        assert!(denied.check_url(&url("http://192.168.0.1/")).is_err());
+    }
+
+    #[test]
+    fn methods_are_checked_when_listed() {
+        assert!(policy().check_method("PATCH").is_ok());
+        let listed = Policy {
+            methods: vec!["GET".to_string()],
+            ..policy()
+        };
+        assert!(listed.check_method("GET").is_ok());
+        assert!(listed.check_method("POST").is_err());
+    }
+
+    #[test]
+    fn embedded_ipv4_addresses_are_unwrapped() {
+        assert_eq!(canonical(ip("::ffff:10.1.2.3")), ip("10.1.2.3"));
+        assert_eq!(canonical(ip("::10.1.2.3")), ip("10.1.2.3"));
+        assert_eq!(canonical(ip("64:ff9b::a9fe:a9fe")), ip("169.254.169.254"));
+        assert_eq!(canonical(ip("2002:7f00:1::1")), ip("127.0.0.1"));
+        assert_eq!(canonical(ip("2001:0:4136:e378:8000:63bf:80ff:fffe")), ip("127.0.0.1"));
+        assert_eq!(canonical(ip("::1")), ip("::1"));
+        assert_eq!(canonical(ip("::")), ip("::"));
+        assert_eq!(canonical(ip("2001:db8::1")), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn internal_addresses_are_refused_however_they_are_written() {
+        let policy = policy();
+        for internal in [
+            "::ffff:127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::192.168.1.1",
+            "2002:a9fe:a9fe::",
+            "::1",
+            "fec0::1",
+            "100::1",
+            "2001:0:4136:e378:8000:63bf:3fff:fdd2",
+        ] {
+            assert!(policy.check_ip(ip(internal)).is_err(), "{}", internal);
+        }
+        for public in ["64:ff9b::8.8.8.8", "2002:0808:0808::1", "2606:4700::1111", "100:0:0:1::1", "2001:4860::8888"] {
+            assert!(policy.check_ip(ip(public)).is_ok(), "{}", public);
+        }
+    }
+
+    #[test]
+    fn blocks_match_addresses_written_either_way() {
+        let policy = Policy {
+            allow_cidrs: vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()],
+            deny_cidrs: vec!["2002::/16".parse().unwrap(), "203.0.113.0/24".parse().unwrap()],
+            ..policy()
+        };
+        assert!(policy.check_ip(ip("64:ff9b::10.0.0.1")).is_ok());
+        assert!(policy.check_ip(ip("::1")).is_ok());
+        assert!(policy.check_ip(ip("2002:0808:0808::1")).is_err());
+        assert!(policy.check_ip(ip("::ffff:203.0.113.9")).is_err());
+
+        // Teredo is internal, so only reachable at all with --allow-internal
+        let open = Policy { allow_internal: true, ..policy };
+        assert!(open.check_ip(ip("2001:0:4136:e378:8000:63bf:34ff:8ef6")).is_err());
+        assert!(open.check_ip(ip("2001:0:4136:e378:8000:63bf:f7f7:f7f7")).is_ok());
+    }
+}
@@ -0,0 +1,115 @@
+use std::time::SystemTime;
+
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use tokio::time::{sleep, timeout_at, Duration, Instant};
+
+use crate::policy::Denied;
+
+const BASE_DELAY: u64 = 250; // Backoff before the first retry, doubling with each one after (milliseconds)
+const MAX_DELAY: u64 = 4000; // Longest backoff between attempts (milliseconds)
+
+/// How a request that never got a usable response ended
+pub enum Failure {
+    Error(reqwest::Error), // The last attempt failed in a way another could not fix
+    TimedOut,              // The deadline passed
+}
+
+/// Sends a request, trying again after failures another attempt may well not
+/// meet: connections refused or reset, and origins answering 502, 503 or 504.
+/// Only requests that are safe to repeat are retried, at most `retries` times,
+/// with growing randomized pauses or as long as the origin asks, and never past
+/// the deadline. A final 502, 503 or 504 is returned like any other response.
+pub async fn send(request: RequestBuilder, retries: u32, deadline: Instant) -> Result<reqwest::Response, Failure> {
+    let (client, request) = request.build_split();
+    let request = request.map_err(Failure::Error)?;
+    let repeatable = is_idempotent(request.method()) || request.body().is_none();
+    let target = request.url().to_string();
+
+    let mut attempt = 0;
+    loop {
+        // Every attempt but the last sends a copy, keeping the request for the next one
+        let Some(copy) = request.try_clone().filter(|_| repeatable && attempt < retries) else {
+            return match timeout_at(deadline, client.execute(request)).await {
+                Ok(outcome) => outcome.map_err(Failure::Error),
+                Err(_) => Err(Failure::TimedOut),
+            };
+        };
+
+        let outcome = match timeout_at(deadline, client.execute(copy)).await {
+            Ok(outcome) => outcome,
+            Err(_) => return Err(Failure::TimedOut),
+        };
+        let Some((reason, asked)) = retry_reason(&outcome) else {
+            return outcome.map_err(Failure::Error);
+        };
+
+        let delay = asked.unwrap_or_else(|| backoff(attempt));
+        attempt += 1;
+        if deadline.saturating_duration_since(Instant::now()) <= delay {
+            println!("🔁 Attempt {} for {} failed ({}), with no time left to retry", attempt, target, reason);
+            return outcome.map_err(Failure::Error);
+        }
+        println!("🔁 Attempt {} for {} failed ({}), retrying in {}ms", attempt, target, reason, delay.as_millis());
+        drop(outcome);
+        sleep(delay).await;
+    }
+}
+
+/// Methods that have the same effect however many times they are sent (RFC 9110 section 9.2.2)
+fn is_idempotent(method: &Method) -> bool {
+    [Method::GET, Method::HEAD, Method::OPTIONS, Method::TRACE, Method::PUT, Method::DELETE].contains(method)
+}
+
+/// Why an attempt is worth repeating, along with how long the origin asked us to wait
+fn retry_reason(outcome: &Result<reqwest::Response, reqwest::Error>) -> Option<(String, Option<Duration>)> {
+    match outcome {
+        Ok(response) => match response.status() {
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => {
+                Some((format!("origin answered {}", response.status()), retry_after(response.headers())))
+            }
+            _ => None,
+        },
+        // The policy will say no again
+        Err(e) if Denied::cause_of(e).is_some() => None,
+        Err(e) if e.is_connect() => Some(("connection failed".to_string(), None)),
+        Err(e) if was_reset(e) => Some(("connection reset".to_string(), None)),
+        Err(_) => None,
+    }
+}
+
+/// Whether a request failed because the origin dropped its connection
+fn was_reset(error: &reqwest::Error) -> bool {
+    let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(error) = cause {
+        if let Some(io) = error.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind::*;
+            if matches!(io.kind(), ConnectionReset | ConnectionAborted | BrokenPipe) {
+                return true;
+            }
+        }
+        if error.downcast_ref::<hyper::Error>().is_some_and(|e| e.is_incomplete_message()) {
+            return true;
+        }
+        cause = error.source();
+    }
+    false
+}
+
+/// The wait a Retry-After header asks for, given as seconds or as a date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// Exponential backoff with jitter: somewhere between half and all of the
+/// doubled delay, so clients retrying together spread out
+fn backoff(attempt: u32) -> Duration {
+    let delay = BASE_DELAY.saturating_mul(1 << attempt.min(16)).min(MAX_DELAY);
+    Duration::from_millis(rand::thread_rng().gen_range(delay / 2..=delay))
+}
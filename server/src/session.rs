@@ -0,0 +1,218 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use tokio::time::{Duration, Instant};
+
+use common::crypto::{Cipher, Direction, Psk};
+use common::session::{self, ClientHello, SessionId};
+use common::structs::{ClientMessage, ServerMessage};
+
+const SESSION_IDLE_TIMEOUT: u64 = 30 * 60; // Sessions unused for this long are dropped (seconds)
+const SESSION_LIFETIME: u64 = 60 * 60; // Sessions older than this are dropped, forcing a new handshake (seconds)
+const MAX_SESSIONS: usize = 10_000; // Sessions kept at once; the least recently used goes to make room
+const HELLO_WINDOW: u64 = 5 * 60; // How far a hello's timestamp may be from our clock (seconds)
+const REPLAY_WINDOW: u64 = 1024; // How far behind the newest message a late one may arrive and still be accepted
+
+struct Session {
+    cipher: Cipher,
+    created: Instant,
+    last_seen: Instant,
+    sequences: Sequences,
+}
+
+impl Session {
+    fn expired(&self, now: Instant) -> bool {
+        now.duration_since(self.created) > Duration::from_secs(SESSION_LIFETIME)
+            || now.duration_since(self.last_seen) > Duration::from_secs(SESSION_IDLE_TIMEOUT)
+    }
+}
+
+/// The sequence numbers a session has accepted lately. Messages may overtake each
+/// other on the way, so any order is fine as long as no number comes twice.
+#[derive(Default)]
+struct Sequences {
+    highest: u64,
+    recent: BTreeSet<u64>, // Those accepted within REPLAY_WINDOW of the highest
+}
+
+impl Sequences {
+    /// Records a sequence number, returning false if it was seen before or is too old to tell
+    fn accept(&mut self, sequence: u64) -> bool {
+        if sequence == 0 || sequence.saturating_add(REPLAY_WINDOW) <= self.highest || !self.recent.insert(sequence) {
+            return false;
+        }
+        if sequence > self.highest {
+            self.highest = sequence;
+            self.recent = self.recent.split_off(&sequence.saturating_sub(REPLAY_WINDOW));
+        }
+        true
+    }
+}
+
+/// What an authenticated client payload turned out to be
+pub enum Opened {
+    Handshake(ClientHello),
+    Message(SessionId, u64, ClientMessage), // With the sequence number the reply is bound to
+}
+
+/// Live client sessions, keyed by the opaque session id handed out in the handshake
+pub struct SessionTable {
+    psk: Psk,
+    handshake_cipher: Cipher,
+    sessions: Mutex<HashMap<SessionId, Session>>,
+    hellos: Mutex<HashMap<String, Instant>>, // Public keys of recent hellos, each answered once
+}
+
+impl SessionTable {
+    pub fn new(psk: &Psk) -> Result<Self, String> {
+        Ok(SessionTable {
+            psk: psk.clone(),
+            handshake_cipher: Cipher::from_psk(psk)?,
+            sessions: Mutex::new(HashMap::new()),
+            hellos: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Authenticates a client payload, either as traffic for a known session or as
+    /// a new handshake. Replays of either are refused.
+    pub fn open(&self, payload: &[u8]) -> Result<Opened, String> {
+        if let Some((id, sequence, sealed)) = session::unframe(payload) {
+            let mut sessions = self.sessions.lock().unwrap();
+            let now = Instant::now();
+
+            if let Some(session) = sessions.get_mut(&id).filter(|s| !s.expired(now)) {
+                let message = session.cipher.open_json(Direction::ClientToServer, sequence, sealed)?;
+                if !session.sequences.accept(sequence) {
+                    return Err(format!("Replayed message {}", sequence));
+                }
+                session.last_seen = now;
+                return Ok(Opened::Message(id, sequence, message));
+            }
+        }
+
+        let hello: ClientHello = self.handshake_cipher.open_json(Direction::ClientToServer, 0, payload)?;
+        if hello.skew() > HELLO_WINDOW {
+            return Err(format!("Handshake sent {}s away from our clock", hello.skew()));
+        }
+        // A hello is only fresh for so long, so only that long needs remembering
+        let now = Instant::now();
+        let mut hellos = self.hellos.lock().unwrap();
+        hellos.retain(|_, seen| now.duration_since(*seen) < Duration::from_secs(2 * HELLO_WINDOW));
+        if hellos.insert(hello.public_key.clone(), now).is_some() {
+            return Err("Replayed handshake".to_string());
+        }
+        Ok(Opened::Handshake(hello))
+    }
+
+    /// Answers a handshake and stores the new session, returning the sealed reply
+    pub fn accept(&self, hello: &ClientHello) -> Result<Vec<u8>, String> {
+        let (reply, id, cipher) = session::accept(&self.psk, hello, SESSION_LIFETIME)?;
+
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| !session.expired(now));
+        if sessions.len() >= MAX_SESSIONS {
+            let idle = sessions.iter().min_by_key(|(_, session)| session.last_seen).map(|(id, _)| *id);
+            if let Some(idle) = idle {
+                sessions.remove(&idle);
+            }
+        }
+        sessions.insert(id, Session {
+            cipher,
+            created: now,
+            last_seen: now,
+            sequences: Sequences::default(),
+        });
+        println!("🤝 New session established ({} active)", sessions.len());
+
+        self.handshake_cipher.seal_json(Direction::ServerToClient, 0, &reply)
+    }
+
+    /// Seals a reply to the message with the given sequence number
+    pub fn seal(&self, id: &SessionId, sequence: u64, response: &ServerMessage) -> Result<Vec<u8>, String> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(id).ok_or_else(|| "Session expired".to_string())?;
+        session.cipher.seal_json(Direction::ServerToClient, sequence, response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::session::{Handshake, ServerHello, SESSION_ID_LEN};
+
+    fn psk() -> &'static Psk {
+        static PSK: std::sync::OnceLock<Psk> = std::sync::OnceLock::new();
+        PSK.get_or_init(|| Psk::stretch("test key").unwrap())
+    }
+
+    /// Runs a handshake against the table, returning the session a client would hold
+    fn handshake(table: &SessionTable) -> (SessionId, Cipher) {
+        let psk_cipher = Cipher::from_psk(psk()).unwrap();
+        let handshake = Handshake::new();
+        let hello = psk_cipher.seal_json(Direction::ClientToServer, 0, &handshake.hello()).unwrap();
+        let Ok(Opened::Handshake(hello)) = table.open(&hello) else { panic!("hello refused") };
+        let reply = table.accept(&hello).unwrap();
+        let reply: ServerHello = psk_cipher.open_json(Direction::ServerToClient, 0, &reply).unwrap();
+        handshake.finish(psk(), &reply).unwrap()
+    }
+
+    fn message(id: &SessionId, cipher: &Cipher, sequence: u64) -> Vec<u8> {
+        let sealed = cipher.seal_json(Direction::ClientToServer, sequence, &ClientMessage::Body { stream: 7 }).unwrap();
+        session::frame(id, sequence, &sealed)
+    }
+
+    #[test]
+    fn messages_open_once_in_any_order() {
+        let table = SessionTable::new(psk()).unwrap();
+        let (id, cipher) = handshake(&table);
+
+        for sequence in [2, 1, 3] {
+            assert!(matches!(table.open(&message(&id, &cipher, sequence)), Ok(Opened::Message(_, s, _)) if s == sequence));
+        }
+        assert!(table.open(&message(&id, &cipher, 2)).is_err());
+        assert!(table.open(&message(&id, &cipher, 0)).is_err());
+    }
+
+    #[test]
+    fn sequence_must_match_the_one_sealed() {
+        let table = SessionTable::new(psk()).unwrap();
+        let (id, cipher) = handshake(&table);
+
+        let mut payload = message(&id, &cipher, 5);
+        payload[SESSION_ID_LEN + 7] = 6; // Claim another sequence number
+        assert!(table.open(&payload).is_err());
+    }
+
+    #[test]
+    fn hellos_are_answered_once() {
+        let table = SessionTable::new(psk()).unwrap();
+        let hello = Cipher::from_psk(psk())
+            .unwrap()
+            .seal_json(Direction::ClientToServer, 0, &Handshake::new().hello())
+            .unwrap();
+
+        assert!(matches!(table.open(&hello), Ok(Opened::Handshake(_))));
+        assert!(table.open(&hello).is_err());
+    }
+
+    #[test]
+    fn stale_hellos_are_refused() {
+        let table = SessionTable::new(psk()).unwrap();
+        let hello = ClientHello { sent: 0, ..Handshake::new().hello() };
+        let hello = Cipher::from_psk(psk()).unwrap().seal_json(Direction::ClientToServer, 0, &hello).unwrap();
+
+        assert!(table.open(&hello).is_err());
+    }
+
+    #[test]
+    fn old_sequences_fall_out_of_the_window() {
+        let mut sequences = Sequences::default();
+        assert!(sequences.accept(1));
+        assert!(sequences.accept(REPLAY_WINDOW + 10));
+        assert!(!sequences.accept(2)); // Too old to know whether it was seen
+        assert!(sequences.accept(20));
+        assert!(!sequences.accept(20));
+        assert!(sequences.recent.len() <= REPLAY_WINDOW as usize + 1);
+    }
+}
@@ -1,27 +1,135 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 use clap::Parser;
+use common::carrier::CarrierKind;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use crate::policy::{Cidr, Ports};
 
 #[derive(Parser)]
 pub struct Cli {
+    /// TOML file setting any of these options, keyed by their long names (see docs/configuration.md)
+    #[clap(long = "config")]
+    pub config: Option<PathBuf>,
+
     /// Port number for the proxy server (defaults to 3030)
     #[clap(short = 'p', long = "port", default_value = "3030")]
     pub port: u16,
-}
 
-/// Structure to receive and parse incoming proxy requests
-#[derive(Deserialize)]
-pub struct ProxyRequest {
-    pub target: String,         // Base64 encoded target URL
-    pub method: String,         // HTTP method (GET, POST, etc.)
-    pub headers: String,        // Base64 encoded JSON string of headers
-    pub body: Option<String>,   // Optional Base64 encoded request body
-}
+    /// Address to listen on, e.g. 0.0.0.0 for every interface
+    #[clap(long = "bind", default_value = "127.0.0.1")]
+    pub bind: IpAddr,
+
+    /// Also serve HTTPS on this port (with a temporary self-signed certificate unless --tls-cert is given)
+    #[clap(long = "tls-port")]
+    pub tls_port: Option<u16>,
+
+    /// PEM certificate chain for HTTPS, reloaded whenever the file changes (a self-signed
+    /// certificate is generated here, along with --tls-key, if neither exists)
+    #[clap(long = "tls-cert", requires_all = ["tls_key", "tls_port"])]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for HTTPS, reloaded whenever the file changes
+    #[clap(long = "tls-key", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Serve only HTTPS, with no plain HTTP port
+    #[clap(long = "tls-only", requires = "tls_port")]
+    pub tls_only: bool,
+
+    /// Carrier used to disguise proxy traffic: query, body, header, png or html (must match the client)
+    #[clap(short = 'c', long = "carrier", default_value = "body")]
+    pub carrier: CarrierKind,
+
+    /// Directory of PNG cover images for the png carrier (generated covers are used if omitted)
+    #[clap(long = "cover-dir")]
+    pub cover_dir: Option<PathBuf>,
+
+    /// Directory of HTML page templates for the html carrier (a built-in article is used if omitted)
+    #[clap(long = "template-dir")]
+    pub template_dir: Option<PathBuf>,
+
+    /// Pre-shared key used to authenticate and encrypt traffic (must match the client)
+    #[clap(long = "psk")]
+    pub psk: String,
+
+    /// Directory served as a static website to anyone who is not an authenticated client
+    #[clap(long = "decoy-dir", conflicts_with = "decoy_url")]
+    pub decoy_dir: Option<PathBuf>,
+
+    /// Website reverse proxied to anyone who is not an authenticated client
+    #[clap(long = "decoy-url")]
+    pub decoy_url: Option<String>,
+
+    /// Hand redirects to the client instead of following them on the server
+    #[clap(long = "no-follow-redirects")]
+    pub no_follow_redirects: bool,
+
+    /// Let clients reach loopback, private, link-local and other internal addresses
+    #[clap(long = "allow-internal")]
+    pub allow_internal: bool,
 
-/// Structure to format and send proxy responses
-#[derive(Serialize)]
-pub struct ProxyResponse {
-    pub status: u16,                        // HTTP status code
-    pub headers: HashMap<String, String>,   // Response headers
-    pub body: String,                       // Base64 encoded response body
-}
\ No newline at end of file
+    /// Internal address blocks clients may reach anyway, e.g. 10.1.0.0/16 (comma separated)
+    #[clap(long = "allow-cidr", value_delimiter = ',')]
+    pub allow_cidrs: Vec<Cidr>,
+
+    /// Address blocks clients may never reach (comma separated)
+    #[clap(long = "deny-cidr", value_delimiter = ',')]
+    pub deny_cidrs: Vec<Cidr>,
+
+    /// The only domains clients may reach, including their subdomains (comma separated)
+    #[clap(long = "allow-domain", value_delimiter = ',')]
+    pub allow_domains: Vec<String>,
+
+    /// Domains clients may never reach, including their subdomains (comma separated)
+    #[clap(long = "deny-domain", value_delimiter = ',')]
+    pub deny_domains: Vec<String>,
+
+    /// The only ports clients may reach, e.g. 80,443,8000-8999 (any port if omitted)
+    #[clap(long = "allow-ports", value_delimiter = ',')]
+    pub allow_ports: Vec<Ports>,
+
+    /// The only request methods forwarded, e.g. GET,HEAD,OPTIONS (any method if omitted).
+    /// Tunnels are not affected.
+    #[clap(long = "allow-methods", value_delimiter = ',')]
+    pub allow_methods: Vec<String>,
+
+    /// Largest request body clients may send, in bytes
+    #[clap(long = "max-request-body", default_value_t = crate::MAX_BODY_SIZE)]
+    pub max_request_body: usize,
+
+    /// Largest response body relayed from an origin, in bytes as the origin sends it (no limit if omitted)
+    #[clap(long = "max-response-body")]
+    pub max_response_body: Option<u64>,
+
+    /// Largest size a compressed response body may decode to, in bytes
+    #[clap(long = "max-decoded-body", default_value_t = crate::MAX_DECODED_SIZE)]
+    pub max_decoded_body: u64,
+
+    /// Most header fields accepted in a request or an origin's response
+    #[clap(long = "max-headers", default_value = "100")]
+    pub max_headers: usize,
+
+    /// Largest header block accepted in a request or an origin's response, in bytes
+    #[clap(long = "max-header-size", default_value = "65536")]
+    pub max_header_size: usize,
+
+    /// Seconds an origin may take to answer a request, and may leave its response body stalled
+    #[clap(long = "request-timeout", default_value_t = crate::REQUEST_TIMEOUT)]
+    pub request_timeout: u64,
+
+    /// Seconds an idle origin connection is kept open for reuse
+    #[clap(long = "pool-idle-timeout", default_value = "30")]
+    pub pool_idle_timeout: u64,
+
+    /// Most idle connections kept open for reuse to each origin
+    #[clap(long = "pool-max-idle", default_value = "32")]
+    pub pool_max_idle: usize,
+
+    /// Most times a request that failed in passing is tried again (only requests safe to repeat are)
+    #[clap(long = "max-retries", default_value_t = crate::MAX_RETRIES)]
+    pub max_retries: u32,
+
+    /// Relay compressed response bodies as they are, leaving decoding to the browser
+    #[clap(long = "pass-encoded")]
+    pub pass_encoded: bool,
+}
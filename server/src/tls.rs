@@ -0,0 +1,214 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::{interval, timeout, Duration};
+use tokio_rustls::rustls::crypto::ring::sign::any_supported_type;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+use common::files;
+
+const RELOAD_CHECK: u64 = 5; // Seconds between checks for a changed certificate or key file
+const HANDSHAKE_TIMEOUT: u64 = 10; // Seconds a connection has to finish the TLS handshake
+const PENDING_CONNECTIONS: usize = 128; // Handshaken connections waiting for warp to pick them up
+
+/// The certificate presented to clients, which can be swapped while the server runs
+#[derive(Debug)]
+pub struct Certificates {
+    files: Option<(PathBuf, PathBuf)>, // Certificate chain and key, or None for a generated certificate
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    /// Loads a PEM certificate chain and private key, or creates a self-signed pair
+    /// there if neither file exists
+    pub fn load_or_generate(cert_path: PathBuf, key_path: PathBuf) -> Result<Self, String> {
+        let current = match (cert_path.exists(), key_path.exists()) {
+            (true, true) => load(&cert_path, &key_path)?,
+            (false, false) => {
+                let (current, cert_pem, key_pem) = self_signed()?;
+                std::fs::write(&cert_path, cert_pem)
+                    .map_err(|e| format!("Failed to write {}: {}", cert_path.display(), e))?;
+                files::write_private(&key_path, &key_pem)?;
+                println!("🔏 Generated a self-signed TLS certificate in {}", cert_path.display());
+                current
+            }
+            _ => {
+                return Err(format!(
+                    "Only one of {} and {} exists; provide both or neither",
+                    cert_path.display(),
+                    key_path.display()
+                ))
+            }
+        };
+
+        Ok(Certificates {
+            files: Some((cert_path, key_path)),
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// A self-signed certificate that only lives as long as the process, for testing
+    pub fn ephemeral() -> Result<Self, String> {
+        let (current, _, _) = self_signed()?;
+        Ok(Certificates {
+            files: None,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// Checks the certificate files for changes every few seconds and loads them
+    /// again when they change. A change that fails to load keeps the old certificate.
+    pub async fn watch(self: Arc<Self>) {
+        let Some((cert_path, key_path)) = &self.files else {
+            return;
+        };
+        let stamps = || (modified(cert_path), modified(key_path));
+
+        let mut seen = stamps();
+        let mut check = interval(Duration::from_secs(RELOAD_CHECK));
+        loop {
+            check.tick().await;
+            let now = stamps();
+            if now == seen {
+                continue;
+            }
+            seen = now;
+
+            match load(cert_path, key_path) {
+                Ok(reloaded) => {
+                    *self.current.write().unwrap() = Arc::new(reloaded);
+                    println!("🔁 Reloaded TLS certificate from {}", cert_path.display());
+                }
+                Err(e) => println!("❌ Failed to reload TLS certificate, keeping the current one: {}", e),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Accepts connections on `listener` and yields them once their TLS handshake
+/// completes. Handshakes run concurrently, so a slow client holds up nobody else.
+pub fn incoming(
+    listener: TcpListener,
+    certificates: Arc<Certificates>,
+) -> impl Stream<Item = Result<TlsStream<tokio::net::TcpStream>, std::io::Error>> {
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(certificates);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let (sender, receiver) = mpsc::channel(PENDING_CONNECTIONS);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    println!("❌ Failed to accept TLS connection: {}", e);
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                // Failed handshakes are scanners and clients that do not trust us; nothing to report
+                if let Ok(Ok(stream)) = timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), acceptor.accept(stream)).await {
+                    let _ = sender.send(stream).await;
+                }
+            });
+        }
+    });
+
+    ReceiverStream::new(receiver).map(Ok)
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, String> {
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Invalid TLS certificate {}: {}", cert_path.display(), e))?;
+    if chain.is_empty() {
+        return Err(format!("No certificates found in {}", cert_path.display()));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| format!("Invalid TLS key {}: {}", key_path.display(), e))?;
+    let key = any_supported_type(&key).map_err(|e| format!("Unsupported TLS key {}: {}", key_path.display(), e))?;
+
+    let certified = CertifiedKey::new(chain, key);
+    certified
+        .keys_match()
+        .map_err(|_| format!("{} is not the key for {}", key_path.display(), cert_path.display()))?;
+    Ok(certified)
+}
+
+/// A certificate for localhost, along with its PEM certificate and key
+fn self_signed() -> Result<(CertifiedKey, String, String), String> {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string(), "127.0.0.1".to_string()])
+        .map_err(|e| e.to_string())?;
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der()));
+    let key = any_supported_type(&key).map_err(|e| e.to_string())?;
+
+    let certified = CertifiedKey::new(vec![generated.cert.der().clone()], key);
+    Ok((certified, generated.cert.pem(), generated.key_pair.serialize_pem()))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    /// Overwrites a file and gives it a modification time of its own, since two
+    /// writes in quick succession can otherwise share one
+    fn rewrite(path: &Path, contents: &str, stamp: u64) {
+        std::fs::write(path, contents).unwrap();
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(stamp)).unwrap();
+    }
+
+    fn served(certificates: &Certificates) -> CertificateDer<'static> {
+        certificates.current.read().unwrap().cert[0].clone()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watch_picks_up_a_rotated_certificate() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        let certificates = Arc::new(Certificates::load_or_generate(cert_path.clone(), key_path.clone()).unwrap());
+        let generated = served(&certificates);
+        tokio::spawn(certificates.clone().watch());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let (rotated, cert_pem, key_pem) = self_signed().unwrap();
+        rewrite(&cert_path, &cert_pem, 1_000_000);
+        rewrite(&key_path, &key_pem, 1_000_000);
+        tokio::time::sleep(Duration::from_secs(RELOAD_CHECK * 2)).await;
+        assert_ne!(served(&certificates), generated);
+        assert_eq!(served(&certificates), rotated.cert[0]);
+
+        // A key that does not load leaves the rotated certificate in place
+        rewrite(&key_path, "not a key", 2_000_000);
+        tokio::time::sleep(Duration::from_secs(RELOAD_CHECK * 2)).await;
+        assert_eq!(served(&certificates), rotated.cert[0]);
+    }
+}
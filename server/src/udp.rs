@@ -0,0 +1,130 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, Duration};
+
+use common::mux::{DatagramReceiver, Endpoint, MAX_DATAGRAM};
+
+use crate::policy::Policy;
+
+const UDP_IDLE_TIMEOUT: u64 = 2 * 60; // Associations with no traffic for this long are closed (seconds)
+
+/// Relays one client UDP association through its own socket, so replies can be
+/// matched to the association they belong to. `initial` holds datagrams that
+/// arrived before the association existed.
+pub async fn relay(
+    endpoint: Arc<Endpoint>,
+    policy: Arc<Policy>,
+    association: u32,
+    mut from_client: DatagramReceiver,
+    initial: Vec<(String, Vec<u8>)>,
+) {
+    // Destinations may be of either family, so there is a socket for each the host supports
+    let v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await;
+    let v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await;
+    let sockets = match (v4, v6) {
+        (Err(e), Err(_)) => {
+            println!("❌ Failed to bind UDP socket: {}", e);
+            endpoint.dissociate(association, true);
+            return;
+        }
+        (v4, v6) => Sockets { v4: v4.ok(), v6: v6.ok() },
+    };
+    println!("📡 New UDP association ({} active)", endpoint.association_count());
+
+    let (mut sent, mut received) = (initial.len(), 0usize);
+    for (peer, data) in initial {
+        send(&sockets, &policy, &peer, &data).await;
+    }
+
+    let (mut buffer_v4, mut buffer_v6) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        tokio::select! {
+            datagram = from_client.recv() => match datagram {
+                Some((peer, data)) => {
+                    send(&sockets, &policy, &peer, &data).await;
+                    sent += 1;
+                }
+                None => break, // The client dissociated
+            },
+            reply = recv_from(sockets.v4.as_ref(), &mut buffer_v4) => {
+                received += forward_reply(&endpoint, association, reply, &buffer_v4);
+            }
+            reply = recv_from(sockets.v6.as_ref(), &mut buffer_v6) => {
+                received += forward_reply(&endpoint, association, reply, &buffer_v6);
+            }
+            _ = sleep(Duration::from_secs(UDP_IDLE_TIMEOUT)) => {
+                // Forget it locally only; if the client sends again a new association starts
+                endpoint.dissociate(association, false);
+                break;
+            }
+        }
+    }
+
+    println!("📡 UDP association closed ({} datagrams out, {} back)", sent, received);
+}
+
+/// The sockets an association sends from, one per address family
+struct Sockets {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+}
+
+impl Sockets {
+    fn for_address(&self, address: &SocketAddr) -> Option<&UdpSocket> {
+        match address {
+            SocketAddr::V4(_) => self.v4.as_ref(),
+            SocketAddr::V6(_) => self.v6.as_ref(),
+        }
+    }
+}
+
+/// Receives from a socket, or waits forever if the family has none
+async fn recv_from(socket: Option<&UdpSocket>, buffer: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buffer).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Hands a reply back to the client, returning how many datagrams were relayed
+fn forward_reply(endpoint: &Endpoint, association: u32, reply: std::io::Result<(usize, SocketAddr)>, buffer: &[u8]) -> usize {
+    match reply {
+        Ok((n, _)) if n > MAX_DATAGRAM => println!("⚠️ Dropped oversized UDP datagram ({} bytes)", n),
+        Ok((n, source)) => {
+            endpoint.send_datagram(association, &source.to_string(), &buffer[..n]);
+            return 1;
+        }
+        Err(e) => println!("❌ UDP receive failed: {}", e),
+    }
+    0
+}
+
+async fn send(sockets: &Sockets, policy: &Policy, peer: &str, data: &[u8]) {
+    if data.len() > MAX_DATAGRAM {
+        println!("⚠️ Dropped oversized UDP datagram ({} bytes)", data.len());
+        return;
+    }
+    let addresses = match policy.resolve(peer).await {
+        Ok(addresses) => addresses,
+        Err(e) => {
+            println!("❌ Dropped UDP datagram to {}: {}", peer, e);
+            return;
+        }
+    };
+    // The first address of a family we have a socket for
+    let Some((socket, address)) = addresses
+        .iter()
+        .find_map(|address| sockets.for_address(address).map(|socket| (socket, address)))
+    else {
+        match addresses.is_empty() {
+            true => println!("❌ Could not resolve UDP destination {}", peer),
+            false => println!("❌ No UDP socket for the address family of {}", peer),
+        }
+        return;
+    };
+    if let Err(e) = socket.send_to(data, address).await {
+        println!("❌ UDP send to {} failed: {}", peer, e);
+    }
+}